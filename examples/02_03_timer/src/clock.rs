@@ -0,0 +1,360 @@
+//! The clocks timers read the time from.
+//!
+//! Timers normally follow the system clock, and are fired by the timer
+//! thread. A test can run them on a `MockClock` instead, which only moves
+//! when told to, so that testing a five-second sleep doesn't take five
+//! seconds. A timer uses the clock entered on the thread which creates it:
+//!
+//! ```
+//! # use example_02_03_timer::{MockClock, TimerFuture};
+//! # use std::time::{Duration, Instant};
+//! let clock = MockClock::new();
+//! let start = Instant::now();
+//! clock.block_on(async {
+//!     TimerFuture::new(Duration::from_secs(3600)).await;
+//! });
+//! assert!(start.elapsed() < Duration::from_secs(1));
+//! ```
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    marker::PhantomData,
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+use crate::driver::{system_driver, Driver};
+
+/// The clock a timer reads the time from, captured when it is created.
+#[derive(Clone)]
+pub(crate) enum Clock {
+    System,
+    Mock(Arc<MockShared>),
+}
+
+/// A clock for tests, which starts out paused at the time it was created.
+///
+/// While paused, the clock only moves when it is `advance`d, or, with
+/// auto-advance on, when a future run by `block_on` has nothing to do but
+/// wait for a timer, in which case the clock jumps straight to the timer's
+/// deadline. Once resumed, the clock runs along with the system clock.
+///
+/// Nothing fires the clock's timers in the background: only `advance` and
+/// `block_on` do.
+#[derive(Clone)]
+pub struct MockClock {
+    shared: Arc<MockShared>,
+}
+
+pub(crate) struct MockShared {
+    time: Mutex<MockTime>,
+    driver: Driver,
+}
+
+struct MockTime {
+    /// The clock's time when it was last paused, resumed or advanced.
+    base: Instant,
+    /// The system time when the clock was resumed, or `None` while paused.
+    resumed: Option<Instant>,
+    auto_advance: bool,
+}
+
+/// Makes a `MockClock` the current clock of this thread until dropped. See
+/// `MockClock::enter`.
+pub struct EnterGuard {
+    previous: Option<Arc<MockShared>>,
+    /// The guard restores the clock of the thread which created it.
+    _not_send: PhantomData<*const ()>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<MockShared>>> = const { RefCell::new(None) };
+}
+
+impl Clock {
+    /// The mock clock entered on this thread, or else the system clock.
+    pub(crate) fn current() -> Clock {
+        CURRENT.with(|current| match &*current.borrow() {
+            Some(shared) => Clock::Mock(shared.clone()),
+            None => Clock::System,
+        })
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        match self {
+            Clock::System => Instant::now(),
+            Clock::Mock(shared) => shared.now(),
+        }
+    }
+
+    pub(crate) fn driver(&self) -> &Driver {
+        match self {
+            Clock::System => system_driver(),
+            Clock::Mock(shared) => &shared.driver,
+        }
+    }
+}
+
+impl MockClock {
+    /// Create a paused clock, with auto-advance on.
+    pub fn new() -> Self {
+        MockClock {
+            shared: Arc::new(MockShared {
+                time: Mutex::new(MockTime {
+                    base: Instant::now(),
+                    resumed: None,
+                    auto_advance: true,
+                }),
+                driver: Driver::new(),
+            }),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.shared.now()
+    }
+
+    /// Stop the clock, until it is resumed.
+    pub fn pause(&self) {
+        let mut time = self.shared.time.lock().unwrap();
+        time.base = time.now();
+        time.resumed = None;
+    }
+
+    /// Let the clock run along with the system clock from its current time.
+    pub fn resume(&self) {
+        let mut time = self.shared.time.lock().unwrap();
+        time.base = time.now();
+        time.resumed = Some(Instant::now());
+    }
+
+    /// Move the clock forward by `duration`, and wake the timers which are
+    /// then due.
+    pub fn advance(&self, duration: Duration) {
+        let mut time = self.shared.time.lock().unwrap();
+        time.base += duration;
+        let now = time.now();
+        drop(time);
+        self.shared.driver.fire(now);
+    }
+
+    /// The earliest deadline of the pending timers on this clock.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.shared.driver.next_deadline()
+    }
+
+    /// Whether `block_on` moves a paused clock to the next timer's deadline
+    /// when its future is waiting for nothing else. On by default.
+    pub fn set_auto_advance(&self, enabled: bool) {
+        self.shared.time.lock().unwrap().auto_advance = enabled;
+    }
+
+    /// Make this the clock of the timers created on this thread, until the
+    /// returned guard is dropped.
+    pub fn enter(&self) -> EnterGuard {
+        let previous = CURRENT.with(|current| current.replace(Some(self.shared.clone())));
+        EnterGuard {
+            previous,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Run `future` to completion on the current thread, with this clock
+    /// entered, firing the clock's timers as they become due. Timers created
+    /// before the call, outside of `enter`, use the system clock instead.
+    ///
+    /// The future is idle when it has returned `Poll::Pending` and nothing
+    /// has woken it since. If the clock is paused with auto-advance on, an
+    /// idle future moves the clock to the next timer's deadline, even if the
+    /// future is also waiting for something else, such as another thread.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let _enter = self.enter();
+        let mut future = pin!(future);
+        let thread_waker = Arc::new(ThreadWaker {
+            thread: thread::current(),
+            woken: AtomicBool::new(true),
+        });
+        let waker = Waker::from(thread_waker.clone());
+        let context = &mut Context::from_waker(&waker);
+        loop {
+            if thread_waker.woken.swap(false, Ordering::SeqCst) {
+                if let Poll::Ready(output) = future.as_mut().poll(context) {
+                    return output;
+                }
+                continue;
+            }
+            // A resumed clock may have reached a deadline in the meantime.
+            if self.shared.driver.fire(self.now()) {
+                continue;
+            }
+            let time = self.shared.time.lock().unwrap();
+            let (paused, auto_advance) = (time.resumed.is_none(), time.auto_advance);
+            drop(time);
+            match self.shared.driver.next_deadline() {
+                Some(deadline) if paused && auto_advance => {
+                    self.advance(deadline.saturating_duration_since(self.now()));
+                }
+                Some(deadline) if !paused => {
+                    thread::park_timeout(deadline.saturating_duration_since(self.now()));
+                }
+                // Wait for a wake, or for another thread to advance the clock.
+                _ => thread::park(),
+            }
+        }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockShared {
+    fn now(&self) -> Instant {
+        self.time.lock().unwrap().now()
+    }
+}
+
+impl MockTime {
+    fn now(&self) -> Instant {
+        match self.resumed {
+            Some(resumed) => self.base + resumed.elapsed(),
+            None => self.base,
+        }
+    }
+}
+
+impl Drop for EnterGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+/// Wakes the thread blocked in `MockClock::block_on`.
+struct ThreadWaker {
+    thread: Thread,
+    /// Set by a wake, and cleared by the thread before it polls again.
+    woken: AtomicBool,
+}
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        // Setting the flag before unparking ensures that the thread sees it
+        // once it wakes up.
+        self.woken.store(true, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TimerFuture;
+    use futures::FutureExt;
+
+    #[test]
+    fn block_on_timer() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let real_start = Instant::now();
+        // Created inside the future, so that the clock is entered.
+        clock.block_on(async { TimerFuture::new(Duration::from_secs(1)).await });
+        assert_eq!(clock.now(), start + Duration::from_secs(1));
+        assert!(real_start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn paused_clock_stands_still() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+    }
+
+    #[test]
+    fn resumed_clock_runs() {
+        let clock = MockClock::new();
+        clock.advance(Duration::from_secs(60));
+        let start = clock.now();
+        clock.resume();
+        thread::sleep(Duration::from_millis(10));
+        clock.pause();
+        let paused = clock.now();
+        assert!(paused >= start + Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(clock.now(), paused);
+    }
+
+    #[test]
+    fn resumed_clock_fires_timers() {
+        let clock = MockClock::new();
+        clock.resume();
+        let real_start = Instant::now();
+        clock.block_on(async { TimerFuture::new(Duration::from_millis(20)).await });
+        assert!(real_start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn advance_fires_due_timers() {
+        let clock = MockClock::new();
+        clock.set_auto_advance(false);
+        let _enter = clock.enter();
+        let mut timers = [
+            TimerFuture::new(Duration::from_secs(1)),
+            TimerFuture::new(Duration::from_secs(2)),
+        ];
+        for timer in &mut timers {
+            assert!(timer.now_or_never().is_none());
+        }
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(clock.shared.driver.next_deadline(), Some(timers[1].deadline()));
+        assert!((&mut timers[0]).now_or_never().is_some());
+        assert!((&mut timers[1]).now_or_never().is_none());
+    }
+
+    #[test]
+    fn block_on_waits_for_manual_advance() {
+        let clock = MockClock::new();
+        clock.set_auto_advance(false);
+        // Created before the advances start, so that its deadline is three
+        // seconds after the clock's starting time.
+        let guard = clock.enter();
+        let timer = TimerFuture::new(Duration::from_secs(3));
+        drop(guard);
+        let advancer = clock.clone();
+        let advances = thread::spawn(move || {
+            for _ in 0..3 {
+                thread::sleep(Duration::from_millis(5));
+                advancer.advance(Duration::from_secs(1));
+            }
+        });
+        clock.block_on(timer);
+        advances.join().unwrap();
+    }
+
+    #[test]
+    fn timers_use_the_entered_clock() {
+        let clock = MockClock::new();
+        let system_timer = TimerFuture::new(Duration::from_secs(1));
+        let guard = clock.enter();
+        let mock_timer = TimerFuture::new(Duration::from_secs(1));
+        drop(guard);
+        clock.advance(Duration::from_secs(1));
+        assert!(mock_timer.now_or_never().is_some());
+        assert!(system_timer.now_or_never().is_none());
+    }
+}
@@ -0,0 +1,387 @@
+//! `TimerFuture` on top of a single shared timer thread.
+//!
+//! The timer from the chapter spawns a thread for every `TimerFuture`, so
+//! 10 000 pending timers means 10 000 sleeping threads. Here, every pending
+//! timer is an entry in one map ordered by deadline, and a single background
+//! thread sleeps until the earliest deadline, wakes the timers which are due,
+//! and goes back to sleep.
+//!
+//! Timers created while a `MockClock` is entered read the time from it
+//! instead, and go in the mock clock's own map, which only the mock clock
+//! fires. See the `clock` module.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::{Condvar, Mutex, MutexGuard, OnceLock},
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::clock::Clock;
+
+/// A future which completes once a duration has elapsed.
+pub struct TimerFuture {
+    clock: Clock,
+    deadline: Instant,
+    /// Tells this timer's entry apart from others with the same deadline.
+    id: u64,
+    /// Whether the timer may have an entry in the driver, which must then be
+    /// removed when the timer is dropped.
+    registered: bool,
+}
+
+/// The pending timers of a clock. The system clock's timers are fired by
+/// the timer thread, and a mock clock's by the mock clock itself.
+pub(crate) struct Driver {
+    state: Mutex<DriverState>,
+    /// Signalled when a timer is registered with an earlier deadline than
+    /// the one the thread is sleeping until.
+    condvar: Condvar,
+}
+
+struct DriverState {
+    /// The waker of every pending timer, by deadline.
+    timers: BTreeMap<(Instant, u64), Waker>,
+}
+
+impl TimerFuture {
+    /// Create a new `TimerFuture` which will complete after the provided
+    /// timeout.
+    pub fn new(duration: Duration) -> Self {
+        let clock = Clock::current();
+        let deadline = clock.now() + duration;
+        new_timer(clock, deadline)
+    }
+
+    /// The instant at which the timer completes.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// The current time on the timer's clock.
+    pub(crate) fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Move the timer to a new deadline, earlier or later, whether or not it
+    /// has completed. A task waiting for the timer doesn't need to poll it
+    /// again: it is woken at the new deadline instead.
+    pub fn reset(&mut self, deadline: Instant) {
+        if self.registered {
+            let driver = self.clock.driver();
+            let mut state = driver.lock();
+            // No entry means the timer has fired, and its task was woken.
+            if let Some(waker) = state.timers.remove(&(self.deadline, self.id)) {
+                // A mock clock which is already past the new deadline won't
+                // fire it, so the task is woken here instead. Checked under
+                // the driver's lock, like in `poll`.
+                if self.clock.now() >= deadline {
+                    drop(state);
+                    waker.wake();
+                } else {
+                    let earliest = state.insert(deadline, self.id, waker);
+                    drop(state);
+                    if earliest {
+                        driver.condvar.notify_one();
+                    }
+                }
+            }
+        }
+        self.deadline = deadline;
+    }
+}
+
+/// Create a new `TimerFuture` which will complete at `deadline`.
+pub fn sleep_until(deadline: Instant) -> TimerFuture {
+    new_timer(Clock::current(), deadline)
+}
+
+fn new_timer(clock: Clock, deadline: Instant) -> TimerFuture {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    TimerFuture {
+        clock,
+        deadline,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        registered: false,
+    }
+}
+
+impl Future for TimerFuture {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // An entry is only removed once its deadline has passed, so the
+        // clock alone says whether the timer has completed.
+        if self.clock.now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        self.registered = true;
+        let driver = self.clock.driver();
+        let mut state = driver.lock();
+        let earliest = match state.timers.get_mut(&(self.deadline, self.id)) {
+            // The future may have moved to another task since the last poll,
+            // but usually it hasn't, and the stored waker can be kept.
+            Some(waker) => {
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+                false
+            }
+            None => state.insert(self.deadline, self.id, cx.waker().clone()),
+        };
+        // The clock may have been advanced, and its due timers fired, since
+        // the check above. `MockClock::advance` moves the time before firing,
+        // so once the entry is in place, either the next `fire` sees it or
+        // this sees the new time.
+        if self.clock.now() >= self.deadline {
+            state.timers.remove(&(self.deadline, self.id));
+            return Poll::Ready(());
+        }
+        drop(state);
+        if earliest {
+            driver.condvar.notify_one();
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for TimerFuture {
+    fn drop(&mut self) {
+        if self.registered {
+            let mut state = self.clock.driver().lock();
+            state.timers.remove(&(self.deadline, self.id));
+        }
+    }
+}
+
+/// Get the system clock's driver, starting the timer thread on first use.
+pub(crate) fn system_driver() -> &'static Driver {
+    static DRIVER: OnceLock<&'static Driver> = OnceLock::new();
+    DRIVER.get_or_init(|| {
+        let driver: &'static Driver = Box::leak(Box::new(Driver::new()));
+        thread::Builder::new()
+            .name("timer".to_string())
+            .spawn(|| driver.run())
+            .expect("failed to start the timer thread");
+        driver
+    })
+}
+
+impl DriverState {
+    /// Add a timer, returning whether it is now the earliest one, in which
+    /// case the timer thread must be told about it.
+    fn insert(&mut self, deadline: Instant, id: u64, waker: Waker) -> bool {
+        let key = (deadline, id);
+        let earliest = self.timers.keys().next().is_none_or(|first| key < *first);
+        self.timers.insert(key, waker);
+        earliest
+    }
+
+    /// Remove the timers which are due at `now`, returning their wakers.
+    fn take_due(&mut self, now: Instant) -> Vec<Waker> {
+        let mut due = Vec::new();
+        while let Some(entry) = self.timers.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            due.push(entry.remove());
+        }
+        due
+    }
+}
+
+impl Driver {
+    pub(crate) fn new() -> Self {
+        Driver {
+            state: Mutex::new(DriverState {
+                timers: BTreeMap::new(),
+            }),
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// The earliest deadline of the pending timers.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.lock().timers.keys().next().map(|&(deadline, _)| deadline)
+    }
+
+    /// Wake the timers which are due at `now`, returning whether there were
+    /// any.
+    pub(crate) fn fire(&self, now: Instant) -> bool {
+        let due = self.lock().take_due(now);
+        let fired = !due.is_empty();
+        // Wake outside of the lock, as waking may poll a timer.
+        due.into_iter().for_each(Waker::wake);
+        fired
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DriverState> {
+        self.state.lock().unwrap()
+    }
+
+    /// The timer thread's loop, for the system clock's driver.
+    fn run(&self) {
+        let mut state = self.lock();
+        loop {
+            let now = Instant::now();
+            let due = state.take_due(now);
+            if !due.is_empty() {
+                // Wake outside of the lock, as waking may poll a timer.
+                drop(state);
+                due.into_iter().for_each(Waker::wake);
+                state = self.lock();
+                continue;
+            }
+            state = match state.timers.keys().next() {
+                Some(&(deadline, _)) => {
+                    self.condvar.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.condvar.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+    use futures::{executor::block_on, future::join_all, FutureExt};
+    use std::{
+        sync::{atomic::AtomicBool, Arc},
+        task::Wake,
+    };
+
+    /// A waker which records whether it was woken. Each clone of its waker
+    /// holds a reference to it, so `Arc::strong_count` counts the clones.
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn flag() -> (Arc<Flag>, Waker) {
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        (flag.clone(), Waker::from(flag))
+    }
+
+    fn poll(timer: &mut TimerFuture, waker: &Waker) -> Poll<()> {
+        Pin::new(timer).poll(&mut Context::from_waker(waker))
+    }
+
+    #[test]
+    fn completes_after_duration() {
+        let start = Instant::now();
+        block_on(TimerFuture::new(Duration::from_millis(50)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn earlier_timer_registered_later() {
+        // The thread is sleeping until the long timer's deadline when the
+        // short one is registered, and must wake up for it.
+        let mut long = TimerFuture::new(Duration::from_secs(10));
+        assert!((&mut long).now_or_never().is_none());
+        let start = Instant::now();
+        block_on(TimerFuture::new(Duration::from_millis(10)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn many_concurrent_timers() {
+        let start = Instant::now();
+        let timers = (0..100_000).map(|i| TimerFuture::new(Duration::from_millis(i % 100)));
+        block_on(join_all(timers));
+        assert!(start.elapsed() >= Duration::from_millis(99));
+    }
+
+    #[test]
+    fn dropping_deregisters() {
+        // Far enough in the future that no other test's timers coincide.
+        let mut timer = TimerFuture::new(Duration::from_secs(3600));
+        let key = (timer.deadline, timer.id);
+        assert!((&mut timer).now_or_never().is_none());
+        assert!(system_driver().lock().timers.contains_key(&key));
+        drop(timer);
+        assert!(!system_driver().lock().timers.contains_key(&key));
+    }
+
+    #[test]
+    fn same_task_keeps_its_waker() {
+        let mut timer = TimerFuture::new(Duration::from_secs(3600));
+        let (flag, waker) = flag();
+        assert!(poll(&mut timer, &waker).is_pending());
+        assert!(poll(&mut timer, &waker).is_pending());
+        // `flag`, `waker` and the single stored clone.
+        assert_eq!(Arc::strong_count(&flag), 3);
+    }
+
+    #[test]
+    fn moved_timer_wakes_new_task() {
+        let mut timer = TimerFuture::new(Duration::from_millis(20));
+        let (old_flag, old_waker) = flag();
+        let (new_flag, new_waker) = flag();
+        assert!(poll(&mut timer, &old_waker).is_pending());
+        assert!(poll(&mut timer, &new_waker).is_pending());
+        // The old task's waker was replaced rather than kept.
+        assert_eq!(Arc::strong_count(&old_flag), 2);
+        while !new_flag.0.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+        assert!(!old_flag.0.load(Ordering::SeqCst));
+        assert!(poll(&mut timer, &new_waker).is_ready());
+    }
+
+    #[test]
+    fn sleep_until_deadline() {
+        let deadline = Instant::now() + Duration::from_millis(20);
+        let timer = sleep_until(deadline);
+        assert_eq!(timer.deadline(), deadline);
+        block_on(timer);
+        assert!(Instant::now() >= deadline);
+    }
+
+    #[test]
+    fn reset_pending_timer() {
+        let mut timer = TimerFuture::new(Duration::from_secs(3600));
+        let (flag, waker) = flag();
+        assert!(poll(&mut timer, &waker).is_pending());
+        let deadline = Instant::now() + Duration::from_millis(20);
+        timer.reset(deadline);
+        assert_eq!(timer.deadline(), deadline);
+        // Woken at the new deadline, without being polled again.
+        while !flag.0.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+        assert!(Instant::now() >= deadline);
+        assert!(poll(&mut timer, &waker).is_ready());
+
+        // A completed timer can be reset too.
+        timer.reset(Instant::now() + Duration::from_secs(3600));
+        assert!(poll(&mut timer, &waker).is_pending());
+    }
+
+    #[test]
+    fn reset_to_passed_deadline_on_mock_clock() {
+        let clock = MockClock::new();
+        clock.set_auto_advance(false);
+        let start = clock.now();
+        let guard = clock.enter();
+        let mut timer = TimerFuture::new(Duration::from_secs(5));
+        drop(guard);
+        let (flag, waker) = flag();
+        assert!(poll(&mut timer, &waker).is_pending());
+        clock.advance(Duration::from_secs(2));
+        // Nothing fires the mock clock again, so the reset itself must wake
+        // the task.
+        timer.reset(start + Duration::from_secs(1));
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert_eq!(clock.next_deadline(), None);
+        assert!(poll(&mut timer, &waker).is_ready());
+    }
+}
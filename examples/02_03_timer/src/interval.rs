@@ -0,0 +1,162 @@
+//! A stream which ticks once every period, built on `TimerFuture`.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::stream::{FusedStream, Stream};
+
+use crate::driver::TimerFuture;
+
+/// What an `Interval` does when its consumer falls behind, and one or more
+/// ticks are already overdue by the time the stream is polled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissedTickBehavior {
+    /// Yield the missed ticks straight away, one per poll, until caught up
+    /// with the original schedule.
+    #[default]
+    Burst,
+    /// Yield one tick now, and restart the schedule from it, so that the next
+    /// tick is a full period later.
+    Delay,
+    /// Yield one tick now, and skip the other missed ticks, so that the next
+    /// tick is the first one of the original schedule still in the future.
+    Skip,
+}
+
+/// A stream of the `Instant`s at which each tick was due. See `interval`.
+pub struct Interval {
+    timer: TimerFuture,
+    period: Duration,
+    missed_tick_behavior: MissedTickBehavior,
+}
+
+/// Create a stream which ticks every `period`, starting immediately.
+///
+/// # Panics
+///
+/// Panics if `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "`interval` period must be non-zero");
+    Interval {
+        timer: TimerFuture::new(Duration::ZERO),
+        period,
+        missed_tick_behavior: MissedTickBehavior::default(),
+    }
+}
+
+impl Interval {
+    /// The time between two ticks.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn missed_tick_behavior(&self) -> MissedTickBehavior {
+        self.missed_tick_behavior
+    }
+
+    pub fn set_missed_tick_behavior(&mut self, behavior: MissedTickBehavior) {
+        self.missed_tick_behavior = behavior;
+    }
+
+    /// When the next tick is due, given the one which was due at `deadline`
+    /// and is being yielded at `now`.
+    fn next_deadline(&self, deadline: Instant, now: Instant) -> Instant {
+        let next = deadline + self.period;
+        if next > now {
+            return next;
+        }
+        match self.missed_tick_behavior {
+            MissedTickBehavior::Burst => next,
+            MissedTickBehavior::Delay => now + self.period,
+            MissedTickBehavior::Skip => {
+                let late = (now - deadline).as_nanos() % self.period.as_nanos();
+                // Less than `period`, so it fits in a `u64`.
+                now + self.period - Duration::from_nanos(late as u64)
+            }
+        }
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Instant>> {
+        ready!(Pin::new(&mut self.timer).poll(cx));
+        let deadline = self.timer.deadline();
+        let next = self.next_deadline(deadline, self.timer.now());
+        self.timer.reset(next);
+        Poll::Ready(Some(deadline))
+    }
+}
+
+impl FusedStream for Interval {
+    fn is_terminated(&self) -> bool {
+        // An interval never ends.
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+    use futures::{FutureExt, StreamExt};
+
+    const PERIOD: Duration = Duration::from_secs(1);
+
+    /// Take the first tick, then fall behind until the third tick is overdue,
+    /// with half a period to spare before the fourth.
+    fn fall_behind(clock: &MockClock, behavior: MissedTickBehavior) -> (Interval, Instant) {
+        let _enter = clock.enter();
+        let mut interval = interval(PERIOD);
+        interval.set_missed_tick_behavior(behavior);
+        let start = clock.block_on(interval.next()).unwrap();
+        clock.advance(PERIOD * 7 / 2);
+        (interval, start)
+    }
+
+    #[test]
+    fn ticks_every_period() {
+        let clock = MockClock::new();
+        let mut interval = clock.block_on(async { interval(PERIOD) });
+        let start = clock.block_on(interval.next()).unwrap();
+        assert_eq!(start, clock.now());
+        for tick in 1..=3 {
+            assert_eq!(clock.block_on(interval.next()), Some(start + PERIOD * tick));
+            assert_eq!(clock.now(), start + PERIOD * tick);
+        }
+    }
+
+    #[test]
+    fn burst_yields_missed_ticks() {
+        let clock = MockClock::new();
+        let (mut interval, start) = fall_behind(&clock, MissedTickBehavior::Burst);
+        for tick in 1..=3 {
+            assert_eq!(interval.next().now_or_never(), Some(Some(start + PERIOD * tick)));
+        }
+        assert_eq!(interval.next().now_or_never(), None);
+        assert_eq!(clock.block_on(interval.next()), Some(start + PERIOD * 4));
+    }
+
+    #[test]
+    fn delay_restarts_schedule() {
+        let clock = MockClock::new();
+        let (mut interval, start) = fall_behind(&clock, MissedTickBehavior::Delay);
+        assert_eq!(interval.next().now_or_never(), Some(Some(start + PERIOD)));
+        assert_eq!(interval.next().now_or_never(), None);
+        let caught_up = start + PERIOD * 7 / 2;
+        assert_eq!(clock.block_on(interval.next()), Some(caught_up + PERIOD));
+    }
+
+    #[test]
+    fn skip_keeps_schedule() {
+        let clock = MockClock::new();
+        let (mut interval, start) = fall_behind(&clock, MissedTickBehavior::Skip);
+        assert_eq!(interval.next().now_or_never(), Some(Some(start + PERIOD)));
+        assert_eq!(interval.next().now_or_never(), None);
+        assert_eq!(clock.block_on(interval.next()), Some(start + PERIOD * 4));
+    }
+}
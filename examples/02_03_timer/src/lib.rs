@@ -1,3 +1,17 @@
+mod clock;
+mod driver;
+mod interval;
+mod timeout;
+
+pub use clock::{EnterGuard, MockClock};
+pub use driver::{sleep_until, TimerFuture};
+pub use interval::{interval, Interval, MissedTickBehavior};
+pub use timeout::{timeout, Elapsed, FutureExt, StreamExt, Timeout, TimeoutStream};
+
+// The timer built step by step in the "Applied: Build a Timer" chapter, which
+// spawns a thread per timer.
+#[cfg(test)]
+mod chapter {
 // ANCHOR: imports
 use std::{
     future::Future,
@@ -46,9 +60,16 @@ impl Future for TimerFuture {
             // to the wrong task, preventing `TimerFuture` from waking up
             // correctly.
             //
-            // N.B. it's possible to check for this using the `Waker::will_wake`
-            // function, but we omit that here to keep things simple.
-            shared_state.waker = Some(cx.waker().clone());
+            // `Waker::will_wake` tells us whether the waker we already have
+            // would wake the current task, so we only need to clone the new
+            // waker when the future has moved.
+            let stale = match &shared_state.waker {
+                Some(waker) => !waker.will_wake(cx.waker()),
+                None => true,
+            };
+            if stale {
+                shared_state.waker = Some(cx.waker().clone());
+            }
             Poll::Pending
         }
     }
@@ -86,6 +107,33 @@ impl TimerFuture {
 #[test]
 fn block_on_timer() {
     futures::executor::block_on(async {
-        TimerFuture::new(Duration::from_secs(1)).await
+        TimerFuture::new(Duration::from_millis(10)).await
     })
 }
+
+#[test]
+fn waker_cloned_only_when_stale() {
+    use futures::task::{waker, ArcWake};
+
+    struct NoopWake;
+    impl ArcWake for NoopWake {
+        fn wake_by_ref(_arc_self: &Arc<Self>) {}
+    }
+
+    // Long enough for the polls below to happen before the timer fires.
+    let timer = &mut TimerFuture::new(Duration::from_millis(50));
+    let (first, second) = (Arc::new(NoopWake), Arc::new(NoopWake));
+    let first_waker = waker(first.clone());
+    for _ in 0..2 {
+        let _ = Pin::new(&mut *timer).poll(&mut Context::from_waker(&first_waker));
+    }
+    // `first`, `first_waker`, and the single clone in the shared state.
+    assert_eq!(Arc::strong_count(&first), 3);
+    let second_waker = waker(second.clone());
+    let _ = Pin::new(&mut *timer).poll(&mut Context::from_waker(&second_waker));
+    assert_eq!(Arc::strong_count(&first), 2);
+    assert_eq!(Arc::strong_count(&second), 3);
+    // Let the timer's thread finish, rather than outlive the test.
+    futures::executor::block_on(timer);
+}
+}
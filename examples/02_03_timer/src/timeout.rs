@@ -0,0 +1,207 @@
+//! Bounding how long a future, or each item of a stream, may take.
+
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::stream::{FusedStream, Stream};
+use pin_project_lite::pin_project;
+
+use crate::driver::TimerFuture;
+
+/// The error returned when a future or stream item doesn't complete in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed(());
+
+/// A future which completes with the output of `future`, or with `Elapsed`
+/// once `duration` has passed, in which case `future` is dropped.
+///
+/// A future which completes in the same poll in which the time runs out
+/// still counts as having completed in time.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        timer: TimerFuture::new(duration),
+    }
+}
+
+pin_project! {
+    /// The future returned by `timeout` and `FutureExt::timeout`.
+    pub struct Timeout<F> {
+        #[pin]
+        future: F,
+        timer: TimerFuture,
+    }
+}
+
+pin_project! {
+    /// The stream returned by `StreamExt::timeout`.
+    pub struct TimeoutStream<S> {
+        #[pin]
+        stream: S,
+        duration: Duration,
+        // Counts down while waiting for the next item. It starts on the first
+        // poll for the item, rather than when the previous item was yielded.
+        timer: Option<TimerFuture>,
+    }
+}
+
+/// Adds `timeout` to every future.
+pub trait FutureExt: Future {
+    /// Like `timeout(duration, self)`.
+    fn timeout(self, duration: Duration) -> Timeout<Self>
+    where
+        Self: Sized,
+    {
+        timeout(duration, self)
+    }
+}
+
+impl<F: Future + ?Sized> FutureExt for F {}
+
+/// Adds `timeout` to every stream.
+pub trait StreamExt: Stream {
+    /// Yield `Err(Elapsed)` whenever the stream takes longer than `duration`
+    /// to produce an item. The stream keeps going after an error, and the
+    /// next item gets another `duration`.
+    fn timeout(self, duration: Duration) -> TimeoutStream<Self>
+    where
+        Self: Sized,
+    {
+        TimeoutStream {
+            stream: self,
+            duration,
+            timer: None,
+        }
+    }
+}
+
+impl<S: Stream + ?Sized> StreamExt for S {}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        // The future goes first, so that it wins if both are ready.
+        if let Poll::Ready(output) = this.future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(this.timer).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed(()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: Stream> Stream for TimeoutStream<S> {
+    type Item = Result<S::Item, Elapsed>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if let Poll::Ready(item) = this.stream.poll_next(cx) {
+            *this.timer = None;
+            return Poll::Ready(item.map(Ok));
+        }
+        let duration = *this.duration;
+        let timer = this.timer.get_or_insert_with(|| TimerFuture::new(duration));
+        match Pin::new(timer).poll(cx) {
+            Poll::Ready(()) => {
+                *this.timer = None;
+                Poll::Ready(Some(Err(Elapsed(()))))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: FusedStream> FusedStream for TimeoutStream<S> {
+    fn is_terminated(&self) -> bool {
+        self.stream.is_terminated()
+    }
+}
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+impl Error for Elapsed {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+    use futures::{
+        future::{pending, poll_fn, ready},
+        stream, FutureExt as _, StreamExt as _,
+    };
+
+    #[test]
+    fn completes_in_time() {
+        let clock = MockClock::new();
+        let future = async {
+            TimerFuture::new(Duration::from_secs(1)).await;
+            5
+        };
+        let result = clock.block_on(async { future.timeout(Duration::from_secs(2)).await });
+        assert_eq!(result, Ok(5));
+    }
+
+    #[test]
+    fn times_out() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let future = pending::<()>();
+        let result = clock.block_on(async { timeout(Duration::from_secs(5), future).await });
+        assert_eq!(result, Err(Elapsed(())));
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+    }
+
+    #[test]
+    fn ready_future_beats_zero_timeout() {
+        let clock = MockClock::new();
+        assert_eq!(clock.block_on(async { ready(5).timeout(Duration::ZERO).await }), Ok(5));
+    }
+
+    #[test]
+    fn completes_in_the_poll_the_timer_fires() {
+        let clock = MockClock::new();
+        let _enter = clock.enter();
+        let mut polls = 0;
+        let future = poll_fn(|_| {
+            polls += 1;
+            if polls == 2 {
+                Poll::Ready(polls)
+            } else {
+                Poll::Pending
+            }
+        });
+        let mut timeout = Box::pin(future.timeout(Duration::from_secs(1)));
+        assert!((&mut timeout).now_or_never().is_none());
+        // The timer fires, and the future is ready by the time it is polled
+        // again.
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(timeout.now_or_never(), Some(Ok(2)));
+    }
+
+    #[test]
+    fn times_out_each_item() {
+        let clock = MockClock::new();
+        let items = stream::iter([1, 4, 1])
+            .then(|secs| async move {
+                TimerFuture::new(Duration::from_secs(secs)).await;
+                secs
+            })
+            .timeout(Duration::from_secs(3));
+        // The second item misses its first timeout, but not its second.
+        assert_eq!(
+            clock.block_on(items.collect::<Vec<_>>()),
+            [Ok(1), Err(Elapsed(())), Ok(4), Ok(1)],
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! Compares spawn-and-complete throughput of the executor, which allocates
+//! an `Arc<Task>` and a boxed future per task, with the slab executor, both
+//! with boxed futures and with a single future type stored inline.
+//!
+//! Run with `cargo bench -p example_02_04_executor --bench spawn`.
+
+use example_02_04_executor::{new_executor_and_spawner, slab::new_slab_executor_and_spawner};
+use futures::{future::BoxFuture, FutureExt};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+const TASKS: usize = 10_000;
+const ROUNDS: usize = 10;
+
+/// Wakes its own task and returns `Pending` once, so that every task is
+/// polled twice.
+#[derive(Default)]
+struct YieldOnce {
+    yielded: bool,
+}
+
+impl Future for YieldOnce {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+fn run_executor() -> Duration {
+    let (executor, spawner) = new_executor_and_spawner();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for _ in 0..TASKS {
+            spawner.spawn(YieldOnce::default());
+        }
+        executor.run_until_stalled();
+    }
+    start.elapsed()
+}
+
+fn run_slab_boxed() -> Duration {
+    let (executor, spawner) = new_slab_executor_and_spawner::<BoxFuture<'static, ()>>(TASKS);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for _ in 0..TASKS {
+            spawner.spawn(YieldOnce::default().boxed()).ok().unwrap();
+        }
+        executor.run_until_stalled();
+    }
+    start.elapsed()
+}
+
+fn run_slab_inline() -> Duration {
+    let (executor, spawner) = new_slab_executor_and_spawner::<YieldOnce>(TASKS);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for _ in 0..TASKS {
+            spawner.spawn(YieldOnce::default()).ok().unwrap();
+        }
+        executor.run_until_stalled();
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, bench: fn() -> Duration) {
+    // Warm up, then keep the best of several runs.
+    bench();
+    let best = (0..5).map(|_| bench()).min().unwrap();
+    let tasks = (TASKS * ROUNDS) as u32;
+    println!("{name:>12}: {:?} per task", best / tasks);
+}
+
+fn main() {
+    report("executor", run_executor);
+    report("slab, boxed", run_slab_boxed);
+    report("slab, inline", run_slab_inline);
+}
@@ -0,0 +1,160 @@
+//! Compares the lock-free `TaskCell` against the `Mutex`-based task storage
+//! from the "Applied: Build an Executor" chapter. Both run on the same
+//! minimal executor loop, so only the cost of the task state is measured.
+//!
+//! Run with `cargo bench -p example_02_04_executor`.
+
+// The module's unit tests are compiled out of a bench without a harness.
+#[allow(dead_code, unused_imports)]
+#[path = "../src/task_cell.rs"]
+mod task_cell;
+
+use futures::{
+    future::BoxFuture,
+    task::{waker_ref, ArcWake},
+    FutureExt,
+};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use task_cell::{RunOutcome, TaskCell};
+
+const TASKS: usize = 1_000;
+const YIELDS: usize = 1_000;
+
+type Queue<T> = Arc<Mutex<VecDeque<Arc<T>>>>;
+
+/// Wakes its own task `wakes` times and returns `Pending` once, so the task
+/// is woken while it is being polled.
+struct YieldNow {
+    wakes: usize,
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        for _ in 0..self.wakes {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+async fn yield_loop(wakes: usize) {
+    for _ in 0..YIELDS {
+        YieldNow {
+            wakes,
+            yielded: false,
+        }
+        .await;
+    }
+}
+
+/// The chapter's design: the future sits behind a `Mutex`, and a separate
+/// flag records whether the task is queued.
+struct MutexTask {
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    scheduled: AtomicBool,
+    queue: Queue<MutexTask>,
+}
+
+impl ArcWake for MutexTask {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        if !arc_self.scheduled.swap(true, Ordering::SeqCst) {
+            arc_self.queue.lock().unwrap().push_back(arc_self.clone());
+        }
+    }
+}
+
+fn run_mutex(wakes: usize) -> Duration {
+    let queue: Queue<MutexTask> = Arc::default();
+    let start = Instant::now();
+    for _ in 0..TASKS {
+        queue.lock().unwrap().push_back(Arc::new(MutexTask {
+            future: Mutex::new(Some(yield_loop(wakes).boxed())),
+            scheduled: AtomicBool::new(true),
+            queue: queue.clone(),
+        }));
+    }
+    loop {
+        let Some(task) = queue.lock().unwrap().pop_front() else {
+            break;
+        };
+        task.scheduled.store(false, Ordering::SeqCst);
+        let mut future_slot = task.future.lock().unwrap();
+        if let Some(mut future) = future_slot.take() {
+            let waker = waker_ref(&task);
+            let context = &mut Context::from_waker(&waker);
+            if future.as_mut().poll(context).is_pending() {
+                *future_slot = Some(future);
+            }
+        }
+    }
+    start.elapsed()
+}
+
+/// The executor's design: the future and the scheduling state live in a
+/// `TaskCell`.
+struct CellTask {
+    future: TaskCell<BoxFuture<'static, ()>>,
+    queue: Queue<CellTask>,
+}
+
+impl ArcWake for CellTask {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        if arc_self.future.wake() {
+            arc_self.queue.lock().unwrap().push_back(arc_self.clone());
+        }
+    }
+}
+
+fn run_cell(wakes: usize) -> Duration {
+    let queue: Queue<CellTask> = Arc::default();
+    let start = Instant::now();
+    for _ in 0..TASKS {
+        queue.lock().unwrap().push_back(Arc::new(CellTask {
+            future: TaskCell::new(yield_loop(wakes).boxed()),
+            queue: queue.clone(),
+        }));
+    }
+    loop {
+        let Some(task) = queue.lock().unwrap().pop_front() else {
+            break;
+        };
+        let waker = waker_ref(&task);
+        let context = &mut Context::from_waker(&waker);
+        let outcome = task
+            .future
+            .run(|future| future.as_mut().poll(context).is_ready());
+        if outcome == RunOutcome::Rescheduled {
+            queue.lock().unwrap().push_back(task.clone());
+        }
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, wakes: usize, bench: fn(usize) -> Duration) {
+    // Warm up, then keep the best of several runs.
+    bench(wakes);
+    let best = (0..5).map(|_| bench(wakes)).min().unwrap();
+    let polls = (TASKS * (YIELDS + 1)) as u32;
+    println!("{name:>6}, {wakes:>2} wakes per poll: {:?} per poll", best / polls);
+}
+
+fn main() {
+    for wakes in [1, 10] {
+        report("mutex", wakes, run_mutex);
+        report("cell", wakes, run_cell);
+    }
+}
@@ -0,0 +1,190 @@
+//! Running a single future to completion on the current thread.
+//!
+//! `block_on` polls the future, and whenever it returns `Poll::Pending`,
+//! parks the thread until the future's waker unparks it. `thread::park` may
+//! also return without anyone calling `unpark`, so the waker additionally
+//! sets a flag, and the thread only polls again once the flag is set.
+
+use std::{
+    cell::Cell,
+    future::Future,
+    pin::pin,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Arc,
+    task::{Context, Poll},
+    thread::{self, Thread},
+    time::Duration,
+};
+
+use timer_future::{timeout, Elapsed};
+
+use crate::raw_waker::{waker_ref, Wake};
+
+/// Wakes the thread blocked in `block_on`.
+struct ThreadWaker {
+    thread: Thread,
+    /// Set by a wake, and cleared by the thread before it polls again.
+    woken: AtomicBool,
+}
+
+impl Wake for ThreadWaker {
+    fn wake_by_ref(self: &Arc<Self>) {
+        // Setting the flag before unparking ensures that the thread sees it
+        // once it wakes up.
+        self.woken.store(true, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+}
+
+thread_local! {
+    /// Whether the current thread is inside `block_on`.
+    static BLOCKING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks the current thread as blocking until dropped, which also happens
+/// when the future panics.
+struct BlockingGuard;
+
+impl BlockingGuard {
+    fn enter() -> Self {
+        if BLOCKING.with(|blocking| blocking.replace(true)) {
+            panic!(
+                "`block_on` cannot be called from within another `block_on`: \
+                 blocking the thread would stop the outer future from making progress"
+            );
+        }
+        BlockingGuard
+    }
+}
+
+impl Drop for BlockingGuard {
+    fn drop(&mut self) {
+        BLOCKING.with(|blocking| blocking.set(false));
+    }
+}
+
+/// Run `future` to completion on the current thread, and return its output.
+///
+/// # Panics
+///
+/// Panics if called from within another `block_on` on the same thread.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let _guard = BlockingGuard::enter();
+    let mut future = pin!(future);
+    let thread_waker = Arc::new(ThreadWaker {
+        thread: thread::current(),
+        woken: AtomicBool::new(true),
+    });
+    let waker = waker_ref(&thread_waker);
+    let context = &mut Context::from_waker(&waker);
+    loop {
+        if thread_waker.woken.swap(false, Ordering::SeqCst) {
+            if let Poll::Ready(output) = future.as_mut().poll(context) {
+                return output;
+            }
+            // The future may have woken itself during the poll.
+            continue;
+        }
+        thread::park();
+    }
+}
+
+/// Like `block_on`, but gives up once `duration` has passed, in which case
+/// `future` is dropped. This is `block_on` of `timer_future::timeout`, and
+/// fails with the same `Elapsed` error.
+///
+/// # Panics
+///
+/// Panics if called from within another `block_on` on the same thread.
+pub fn block_on_timeout<F: Future>(future: F, duration: Duration) -> Result<F::Output, Elapsed> {
+    block_on(timeout(duration, future))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{pending, poll_fn};
+    use std::{
+        panic::{catch_unwind, AssertUnwindSafe},
+        sync::atomic::AtomicUsize,
+        sync::Mutex,
+        task::Waker,
+        time::Instant,
+    };
+    use timer_future::TimerFuture;
+
+    #[test]
+    fn returns_output() {
+        assert_eq!(block_on(async { 7 }), 7);
+    }
+
+    #[test]
+    fn woken_from_another_thread() {
+        block_on(TimerFuture::new(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn spurious_unparks_do_not_poll() {
+        let polls = AtomicUsize::new(0);
+        let ready = Arc::new(AtomicBool::new(false));
+        let waker: Arc<Mutex<Option<Waker>>> = Arc::default();
+        let blocked = thread::current();
+        let (thread_ready, thread_waker) = (ready.clone(), waker.clone());
+        let unparker = thread::spawn(move || {
+            // Wait for the first poll, then unpark the thread without waking
+            // the future.
+            while thread_waker.lock().unwrap().is_none() {
+                thread::yield_now();
+            }
+            for _ in 0..100 {
+                blocked.unpark();
+                thread::sleep(Duration::from_micros(100));
+            }
+            thread_ready.store(true, Ordering::SeqCst);
+            thread_waker.lock().unwrap().take().unwrap().wake();
+        });
+        block_on(poll_fn(|cx| {
+            polls.fetch_add(1, Ordering::SeqCst);
+            if ready.load(Ordering::SeqCst) {
+                return Poll::Ready(());
+            }
+            *waker.lock().unwrap() = Some(cx.waker().clone());
+            Poll::Pending
+        }));
+        unparker.join().unwrap();
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot be called from within another `block_on`")]
+    fn nested_block_on_panics() {
+        block_on(async { block_on(async {}) });
+    }
+
+    #[test]
+    fn usable_after_nested_panic() {
+        let result = catch_unwind(AssertUnwindSafe(|| block_on(async { block_on(async {}) })));
+        assert!(result.is_err());
+        assert_eq!(block_on(async { 1 }), 1);
+    }
+
+    #[test]
+    fn timeout_elapses() {
+        let start = Instant::now();
+        let result = block_on_timeout(pending::<()>(), Duration::from_millis(20));
+        assert!(matches!(result, Err(Elapsed { .. })));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn timeout_not_reached() {
+        let result = block_on_timeout(
+            async {
+                TimerFuture::new(Duration::from_millis(10)).await;
+                "done"
+            },
+            Duration::from_secs(5),
+        );
+        assert_eq!(result, Ok("done"));
+    }
+}
@@ -0,0 +1,305 @@
+//! Running blocking code without blocking the executor.
+//!
+//! A task which calls a blocking function, such as `std::fs::read_to_string`,
+//! holds on to the executor thread polling it until the call returns, and no
+//! other task on that thread makes progress in the meantime. `spawn_blocking`
+//! moves such calls onto a separate pool of threads instead, and returns a
+//! `JoinHandle` which can be awaited from any executor.
+//!
+//! The pool is elastic: it starts a new thread whenever a closure is
+//! submitted and every existing thread is busy, up to a maximum, and threads
+//! exit again once they have been idle for a while.
+
+use futures::task::noop_waker_ref;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    panic::{catch_unwind, AssertUnwindSafe},
+    pin::pin,
+    sync::{Arc, Condvar, Mutex, OnceLock},
+    task::Context,
+    thread,
+    time::Duration,
+};
+
+use crate::join_handle::{with_join_handle, JoinHandle};
+
+/// Maximum number of threads in the pool used by `spawn_blocking`.
+const DEFAULT_MAX_THREADS: usize = 64;
+
+/// How long an idle thread waits for more work before exiting, by default.
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// A pool of threads for running blocking closures. See the module docs.
+///
+/// Dropping the pool lets its threads exit once the closures already
+/// submitted have run.
+pub struct BlockingPool {
+    shared: Arc<PoolShared>,
+}
+
+struct PoolShared {
+    state: Mutex<PoolState>,
+    /// Signalled when a job is queued or the pool shuts down.
+    work: Condvar,
+    max_threads: usize,
+    keep_alive: Duration,
+}
+
+struct PoolState {
+    queue: VecDeque<Job>,
+    /// Number of threads alive, whether busy or idle.
+    threads: usize,
+    /// Number of threads waiting for a job.
+    idle: usize,
+    shutdown: bool,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Run `f` on the shared blocking pool, returning a `JoinHandle` which
+/// resolves to its result.
+///
+/// The shared pool runs at most 64 closures at once; the rest wait in a
+/// queue. Use a `BlockingPool` to pick a different limit.
+///
+/// ```
+/// # use example_02_04_executor::{block_on, spawn_blocking};
+/// let len = block_on(async {
+///     let contents = spawn_blocking(|| "a file's contents".to_string());
+///     contents.await.unwrap().len()
+/// });
+/// assert_eq!(len, 17);
+/// ```
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    static POOL: OnceLock<BlockingPool> = OnceLock::new();
+    POOL.get_or_init(|| BlockingPool::new(DEFAULT_MAX_THREADS))
+        .spawn(f)
+}
+
+impl BlockingPool {
+    /// Create a pool which runs at most `max_threads` closures at once.
+    /// Threads are only started when they are needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_threads` is zero.
+    pub fn new(max_threads: usize) -> Self {
+        assert!(max_threads > 0, "a `BlockingPool` needs at least one thread");
+        BlockingPool {
+            shared: Arc::new(PoolShared {
+                state: Mutex::new(PoolState {
+                    queue: VecDeque::new(),
+                    threads: 0,
+                    idle: 0,
+                    shutdown: false,
+                }),
+                work: Condvar::new(),
+                max_threads,
+                keep_alive: DEFAULT_KEEP_ALIVE,
+            }),
+        }
+    }
+
+    /// Set how long a thread waits for another closure before exiting. The
+    /// default is 10 seconds.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("no threads have been started yet")
+            .keep_alive = keep_alive;
+        self
+    }
+
+    /// Run `f` on the pool, returning a `JoinHandle` which resolves to its
+    /// result.
+    ///
+    /// If `f` panics, the handle resolves to `JoinError::Panicked`. Aborting
+    /// the handle before `f` has started means it never runs; once it has
+    /// started, it runs to completion regardless.
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // Reuse the machinery of spawned futures: the future below completes
+        // the first time it is polled, and an aborted one is dropped unpolled.
+        let (task, handle) = with_join_handle(async move { f() });
+        let panic_reporter = handle.panic_reporter();
+        let job = Box::new(move || {
+            let mut task = pin!(task);
+            let cx = &mut Context::from_waker(noop_waker_ref());
+            if let Err(payload) = catch_unwind(AssertUnwindSafe(|| task.as_mut().poll(cx))) {
+                panic_reporter.report(payload);
+            }
+        });
+
+        let mut state = self.shared.state.lock().unwrap();
+        state.queue.push_back(job);
+        // Every idle thread will take one job, so only start another thread
+        // if there are more queued jobs than that.
+        let start_thread =
+            state.queue.len() > state.idle && state.threads < self.shared.max_threads;
+        if start_thread {
+            state.threads += 1;
+        }
+        drop(state);
+        self.shared.work.notify_one();
+        if start_thread {
+            let shared = self.shared.clone();
+            thread::Builder::new()
+                .name("blocking".to_string())
+                .spawn(move || shared.run_worker())
+                .expect("failed to start a blocking thread");
+        }
+        handle
+    }
+
+    /// Number of threads currently in the pool, whether busy or idle.
+    pub fn thread_count(&self) -> usize {
+        self.shared.state.lock().unwrap().threads
+    }
+}
+
+impl Drop for BlockingPool {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.work.notify_all();
+    }
+}
+
+impl PoolShared {
+    fn run_worker(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.queue.pop_front() {
+                drop(state);
+                job();
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            if state.shutdown {
+                break;
+            }
+            state.idle += 1;
+            let (guard, timeout) = self.work.wait_timeout(state, self.keep_alive).unwrap();
+            state = guard;
+            state.idle -= 1;
+            if timeout.timed_out() && state.queue.is_empty() {
+                break;
+            }
+        }
+        state.threads -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block_on, new_executor_and_spawner};
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        sync::Barrier,
+        time::Instant,
+    };
+
+    #[test]
+    fn does_not_block_the_executor() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let blocking_log = log.clone();
+        spawner.spawn(async move {
+            let slept = spawn_blocking(|| thread::sleep(Duration::from_millis(50)));
+            slept.await.unwrap();
+            blocking_log.lock().unwrap().push("blocking");
+        });
+        let other_log = log.clone();
+        spawner.spawn(async move { other_log.lock().unwrap().push("other") });
+        drop(spawner);
+        executor.run();
+        assert_eq!(*log.lock().unwrap(), ["other", "blocking"]);
+    }
+
+    #[test]
+    fn grows_up_to_max_threads() {
+        let pool = BlockingPool::new(4);
+        let barrier = Arc::new(Barrier::new(4));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let barrier = barrier.clone();
+                // Only returns once all four closures run at the same time.
+                pool.spawn(move || barrier.wait())
+            })
+            .collect();
+        for handle in handles {
+            block_on(handle).unwrap();
+        }
+        assert_eq!(pool.thread_count(), 4);
+
+        // More closures than threads: the rest wait in the queue.
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..20)
+            .map(|_| {
+                let (running, most_running) = (running.clone(), most_running.clone());
+                pool.spawn(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(5));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for handle in handles {
+            block_on(handle).unwrap();
+        }
+        assert!(most_running.load(Ordering::SeqCst) <= 4);
+        assert_eq!(pool.thread_count(), 4);
+    }
+
+    #[test]
+    fn idle_threads_are_reaped() {
+        let pool = BlockingPool::new(2).keep_alive(Duration::from_millis(20));
+        block_on(pool.spawn(|| {})).unwrap();
+        assert_eq!(pool.thread_count(), 1);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.thread_count() > 0 {
+            assert!(Instant::now() < deadline, "idle thread was never reaped");
+            thread::sleep(Duration::from_millis(5));
+        }
+        // A new thread is started for the next closure.
+        assert_eq!(block_on(pool.spawn(|| 1)).unwrap(), 1);
+    }
+
+    #[test]
+    fn panic_is_reported() {
+        let pool = BlockingPool::new(1);
+        let error = block_on(pool.spawn(|| panic!("boom"))).unwrap_err();
+        assert!(error.is_panic());
+        assert_eq!(error.to_string(), "task panicked: boom");
+        // The thread survives the panic.
+        assert_eq!(block_on(pool.spawn(|| 2)).unwrap(), 2);
+        assert_eq!(pool.thread_count(), 1);
+    }
+
+    #[test]
+    fn abort_before_start() {
+        let pool = BlockingPool::new(1);
+        let barrier = Arc::new(Barrier::new(2));
+        let thread_barrier = barrier.clone();
+        let busy = pool.spawn(move || {
+            thread_barrier.wait();
+        });
+        let ran = Arc::new(AtomicUsize::new(0));
+        let queued_ran = ran.clone();
+        let queued = pool.spawn(move || queued_ran.fetch_add(1, Ordering::SeqCst));
+        queued.abort();
+        barrier.wait();
+        block_on(busy).unwrap();
+        assert!(block_on(queued).unwrap_err().is_cancelled());
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+    }
+}
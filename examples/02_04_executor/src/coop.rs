@@ -0,0 +1,280 @@
+//! Cooperative scheduling.
+//!
+//! `Executor` polls whichever task it takes off the ready queue, and only
+//! moves on once that task returns `Poll::Pending`. A task whose futures are
+//! always ready, such as a loop receiving from a channel which is never
+//! empty, would therefore starve every other task.
+//!
+//! To prevent this, each poll of a task gets a budget. Leaf futures spend one
+//! unit of it every time they are polled, through `poll_proceed`. Once the
+//! budget is used up, they return `Poll::Pending` instead of making progress,
+//! and the executor puts the task at the back of the ready queue, so that it
+//! gets to run the other tasks first.
+//!
+//! `JoinHandle` spends the budget, as do futures and streams wrapped in
+//! `cooperative`. A future which never touches the budget, such as
+//! `future::ready`, can't be interrupted, so loops over nothing else should
+//! await `consume_budget`.
+//!
+//! Outside of an executor which sets a budget, nothing is ever refused.
+
+use futures::{future::poll_fn, ready, Stream};
+use pin_project_lite::pin_project;
+use std::{
+    cell::Cell,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Number of operations a task may perform each time it is polled.
+const BUDGET: u32 = 128;
+
+thread_local! {
+    /// The budget left for the task being polled on this thread, or `None`
+    /// if no budget applies.
+    static REMAINING: Cell<Option<u32>> = const { Cell::new(None) };
+
+    /// Whether a future was refused since the budget was last set.
+    static EXHAUSTED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `poll` with a fresh budget. Executors call this around every poll of
+/// a task, and must requeue the task if the returned flag is set: some
+/// future then returned `Poll::Pending` only because the budget was used up.
+pub(crate) fn with_budget<R>(poll: impl FnOnce() -> R) -> (R, bool) {
+    /// Puts the previous budget back, even if `poll` panics.
+    struct Restore(Option<u32>, bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            REMAINING.with(|remaining| remaining.set(self.0));
+            EXHAUSTED.with(|exhausted| exhausted.set(self.1));
+        }
+    }
+
+    let _restore = Restore(
+        REMAINING.with(|remaining| remaining.replace(Some(BUDGET))),
+        EXHAUSTED.with(|exhausted| exhausted.replace(false)),
+    );
+    let output = poll();
+    (output, EXHAUSTED.with(Cell::get))
+}
+
+/// Spend one unit of the current task's budget. Returns `Poll::Pending` if
+/// the budget is used up, in which case the executor polls the task again
+/// later.
+///
+/// Leaf futures call this before doing any work.
+pub fn poll_proceed(cx: &mut Context<'_>) -> Poll<()> {
+    REMAINING.with(|remaining| match remaining.get() {
+        None => Poll::Ready(()),
+        Some(0) => {
+            EXHAUSTED.with(|exhausted| exhausted.set(true));
+            // The executor requeues the task, but a combinator which polls
+            // this future with a waker of its own, such as `scope`, only
+            // polls it again once that waker is woken.
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+        Some(left) => {
+            remaining.set(Some(left - 1));
+            Poll::Ready(())
+        }
+    })
+}
+
+/// Spend one unit of the current task's budget, yielding to the executor if
+/// it is used up. Loops which never wait on anything else can await this on
+/// every iteration.
+pub async fn consume_budget() {
+    poll_fn(poll_proceed).await
+}
+
+/// Yield to the executor once, letting the other ready tasks run before the
+/// current one continues.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// The future returned by `yield_now`.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+pin_project! {
+    /// A future or stream which spends the task's budget every time it is
+    /// polled. See `cooperative`.
+    pub struct Cooperative<T> {
+        #[pin]
+        inner: T,
+    }
+}
+
+/// Make a leaf future or stream, such as a channel receiver, respect the
+/// task's budget.
+pub fn cooperative<T>(inner: T) -> Cooperative<T> {
+    Cooperative { inner }
+}
+
+impl<F: Future> Future for Cooperative<F> {
+    type Output = F::Output;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        ready!(poll_proceed(cx));
+        self.project().inner.poll(cx)
+    }
+}
+
+impl<S: Stream> Stream for Cooperative<S> {
+    type Item = S::Item;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        ready!(poll_proceed(cx));
+        self.project().inner.poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block_on, new_executor_and_spawner};
+    use futures::{channel::mpsc, task::noop_waker_ref, StreamExt};
+    use std::{
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use timer_future::TimerFuture;
+
+    #[test]
+    fn busy_loop_and_timer_both_progress() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let spins = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+        let (busy_spins, busy_done) = (spins.clone(), done.clone());
+        spawner.spawn(async move {
+            // Never waits on anything, so without a budget the timer task
+            // would never run and this loop would never end.
+            while !busy_done.load(Ordering::SeqCst) {
+                busy_spins.fetch_add(1, Ordering::SeqCst);
+                consume_budget().await;
+            }
+        });
+        let timer_spins = spins.clone();
+        let ticks = spawner.spawn(async move {
+            let mut spins_at_tick = Vec::new();
+            for _ in 0..3 {
+                TimerFuture::new(Duration::from_millis(10)).await;
+                spins_at_tick.push(timer_spins.load(Ordering::SeqCst));
+            }
+            done.store(true, Ordering::SeqCst);
+            spins_at_tick
+        });
+        drop(spawner);
+        executor.run();
+        let spins_at_tick = block_on(ticks).unwrap();
+        assert!(spins_at_tick[0] > 0);
+        assert!(spins_at_tick.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn channel_receive_yields_when_budget_is_spent() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (sender, receiver) = mpsc::unbounded();
+        for i in 0..1_000 {
+            sender.unbounded_send(i).unwrap();
+        }
+        drop(sender);
+        let received = Arc::new(AtomicUsize::new(0));
+        let consumer_received = received.clone();
+        spawner.spawn(async move {
+            let mut receiver = cooperative(receiver);
+            while receiver.next().await.is_some() {
+                consumer_received.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        // Spawned second, so it only runs once the consumer yields.
+        let seen = spawner.spawn(async move { received.load(Ordering::SeqCst) });
+        drop(spawner);
+        executor.run();
+        assert_eq!(block_on(seen).unwrap(), BUDGET as usize);
+    }
+
+    #[test]
+    fn join_handle_loop_yields_when_budget_is_spent() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let handles: Vec<_> = (0..1_000).map(|_| spawner.spawn(async {})).collect();
+        executor.run_until_stalled();
+        let awaited = Arc::new(AtomicUsize::new(0));
+        let loop_awaited = awaited.clone();
+        spawner.spawn(async move {
+            // Every handle is ready, and the loop doesn't spend the budget
+            // itself.
+            for handle in handles {
+                handle.await.unwrap();
+                loop_awaited.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let seen = spawner.spawn(async move { awaited.load(Ordering::SeqCst) });
+        drop(spawner);
+        executor.run();
+        assert_eq!(block_on(seen).unwrap(), BUDGET as usize);
+    }
+
+    #[test]
+    fn exhausted_task_is_requeued_without_its_waker() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let spins = Arc::new(AtomicUsize::new(0));
+        let busy_spins = spins.clone();
+        spawner.spawn(async move {
+            for _ in 0..2 * BUDGET {
+                // Spends the budget with a waker that isn't the task's, so
+                // only the executor can schedule the task again.
+                poll_fn(|_| poll_proceed(&mut Context::from_waker(noop_waker_ref()))).await;
+                busy_spins.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        drop(spawner);
+        executor.run();
+        assert_eq!(spins.load(Ordering::SeqCst), 2 * BUDGET as usize);
+    }
+
+    #[test]
+    fn yield_now_interleaves_tasks() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for name in ["a", "b"] {
+            let log = log.clone();
+            spawner.spawn(async move {
+                for _ in 0..3 {
+                    log.lock().unwrap().push(name);
+                    yield_now().await;
+                }
+            });
+        }
+        drop(spawner);
+        executor.run();
+        assert_eq!(*log.lock().unwrap(), ["a", "b", "a", "b", "a", "b"]);
+    }
+
+    #[test]
+    fn no_budget_outside_executor() {
+        let mut receiver = cooperative(futures::stream::iter(0..1_000));
+        block_on(async {
+            for i in 0..1_000 {
+                assert_eq!(receiver.next().await, Some(i));
+            }
+        });
+    }
+}
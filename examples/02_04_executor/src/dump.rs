@@ -0,0 +1,89 @@
+use std::{fmt, thread::Thread, time::Duration};
+
+/// A report of every live task on an `Executor`, taken by
+/// `Executor::dump_tasks`. Its `Display` output lists one task per line.
+#[derive(Clone, Debug)]
+pub struct TaskDump {
+    /// The live tasks, in the order they were spawned.
+    pub tasks: Vec<TaskInfo>,
+}
+
+/// What `Executor::dump_tasks` knows about a task.
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    /// Identifies the task. Ids are assigned in the order tasks are spawned.
+    pub id: usize,
+    /// The name given with `TaskBuilder::name`.
+    pub name: Option<String>,
+    pub state: TaskState,
+    /// Time since the task was last polled, or `None` if it hasn't been
+    /// polled since `Executor::enable_task_dump` was called.
+    pub since_last_poll: Option<Duration>,
+    /// What last woke the task, or `None` if it hasn't been woken since it
+    /// was spawned and `Executor::enable_task_dump` was called.
+    pub last_woken_by: Option<WakeSource>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    /// In the ready queue, waiting to be polled.
+    Queued,
+    /// Being polled.
+    Running,
+    /// Waiting to be woken.
+    Idle,
+}
+
+/// Where a wake came from.
+#[derive(Clone, Debug)]
+pub enum WakeSource {
+    /// A task on the same executor, identified by its id. This includes a
+    /// task waking itself.
+    Task(usize),
+    /// A thread which wasn't polling one of the executor's tasks, such as a
+    /// timer thread.
+    Thread(Thread),
+}
+
+impl fmt::Display for TaskDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} live tasks", self.tasks.len())?;
+        for task in &self.tasks {
+            write!(f, "  task {}", task.id)?;
+            if let Some(name) = &task.name {
+                write!(f, " {name:?}")?;
+            }
+            write!(f, ": {}", task.state)?;
+            match task.since_last_poll {
+                Some(elapsed) => write!(f, ", last polled {elapsed:?} ago")?,
+                None => write!(f, ", never polled")?,
+            }
+            match &task.last_woken_by {
+                Some(WakeSource::Task(id)) => {
+                    write!(f, ", last woken by task {id}")?;
+                    let waker = self.tasks.iter().find(|task| task.id == *id);
+                    if let Some(name) = waker.and_then(|task| task.name.as_ref()) {
+                        write!(f, " {name:?}")?;
+                    }
+                }
+                Some(WakeSource::Thread(thread)) => match thread.name() {
+                    Some(name) => write!(f, ", last woken by thread {name:?}")?,
+                    None => write!(f, ", last woken by thread {:?}", thread.id())?,
+                },
+                None => {}
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskState::Queued => write!(f, "queued"),
+            TaskState::Running => write!(f, "running"),
+            TaskState::Idle => write!(f, "idle"),
+        }
+    }
+}
@@ -0,0 +1,1486 @@
+use futures::future::{poll_fn, BoxFuture, FutureExt};
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    pin::pin,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    sync::{Arc, Mutex, OnceLock, Weak},
+    task::{Context, Poll},
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+use crate::coop;
+use crate::dump::{TaskDump, TaskInfo, TaskState, WakeSource};
+use crate::join_handle::{panic_message, with_join_handle, JoinHandle, PanicReporter, Schedule};
+use crate::metrics::{ExecutorMetrics, Recorder, TaskCounters};
+use crate::raw_waker::{waker_ref, Wake};
+use crate::ready_queue::{Priority, ReadyQueue, Rejected};
+use crate::task_cell::{Phase, RunOutcome, TaskCell};
+
+/// Task executor that receives tasks off of a ready queue and runs them.
+pub struct Executor {
+    shared: Arc<Shared>,
+    panic_policy: PanicPolicy,
+}
+
+/// What an `Executor` does when a task panics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Print the panic to stderr, resolve the task's `JoinHandle` to
+    /// `JoinError::Panicked`, and keep running the other tasks.
+    #[default]
+    Log,
+    /// Abort the process.
+    Abort,
+    /// Drop the task and resume the panic from the `Executor` method that
+    /// polled it. The task's `JoinHandle` resolves to `JoinError::Cancelled`.
+    Propagate,
+}
+
+/// `Spawner` spawns new futures onto the ready queue.
+pub struct Spawner {
+    shared: Arc<Shared>,
+}
+
+/// A future that can reschedule itself to be polled by an `Executor`.
+struct Task {
+    /// In-progress future that should be pushed to completion.
+    ///
+    /// The cell also tracks whether the task is queued or running. Waking a
+    /// task which is already queued does nothing, so the queue never holds
+    /// more entries than there are live tasks, no matter how often they are
+    /// woken.
+    future: TaskCell<BoxFuture<'static, ()>>,
+
+    /// Identifies the task in `Shared::tasks`.
+    id: usize,
+
+    /// The name given with `TaskBuilder::name`.
+    name: Option<String>,
+
+    /// Which of the ready queues the task goes on when it is woken.
+    priority: Priority,
+
+    /// When the task was last polled, in nanoseconds since `Shared::created`
+    /// plus one, or zero if it hasn't been polled since task dumps were
+    /// enabled.
+    last_polled: AtomicU64,
+
+    /// What last woke the task, since task dumps were enabled.
+    last_woken_by: Mutex<Option<WakeSource>>,
+
+    /// Passes the payload of a panic in `future` on to its `JoinHandle`.
+    panic_reporter: PanicReporter,
+
+    /// `None` unless metrics were enabled when the task was spawned.
+    metrics: Option<TaskCounters>,
+
+    /// Handle to place the task itself back onto the ready queue.
+    shared: Arc<Shared>,
+}
+
+/// State shared between the executor, its spawners and its tasks.
+struct Shared {
+    ready_queue: ReadyQueue<Arc<Task>>,
+
+    /// Every live task, in the order they were spawned.
+    tasks: Mutex<BTreeMap<usize, Weak<Task>>>,
+    next_id: AtomicUsize,
+
+    /// Set by `Executor::enable_metrics`.
+    metrics: OnceLock<Recorder>,
+
+    /// Set by `Executor::enable_task_dump`. Until then, tasks don't record
+    /// when they were polled and what woke them, which would cost a lock on
+    /// every wake and a clock read on every poll.
+    task_dump: AtomicBool,
+
+    created: Instant,
+}
+
+/// A handle to an `Executor`, which can be cloned and sent to other threads
+/// to spawn tasks onto it. Get one with `Handle::current` or
+/// `Executor::handle`.
+///
+/// Unlike a `Spawner`, a `Handle` doesn't keep `Executor::run` going.
+#[derive(Clone)]
+pub struct Handle {
+    shared: Arc<Shared>,
+}
+
+/// Makes an executor the current one on this thread until it is dropped.
+/// Returned by `Executor::enter`.
+pub struct EnterGuard {
+    /// The executor which was current before, which is restored on drop.
+    previous: Option<Handle>,
+    /// The guard must be dropped on the thread which created it.
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Spawns a task with extra options, such as a name. Created by
+/// `Spawner::builder`.
+pub struct TaskBuilder<'a> {
+    spawner: &'a Spawner,
+    name: Option<String>,
+    priority: Priority,
+}
+
+thread_local! {
+    /// The executor and id of the task being polled on this thread, so that
+    /// wakes can record which task they came from.
+    static CURRENT_TASK: Cell<Option<(*const Shared, usize)>> = const { Cell::new(None) };
+
+    /// This thread, for wakes which don't come from a task.
+    static CURRENT_THREAD: Thread = thread::current();
+
+    /// The executor entered on this thread, which `spawn` and
+    /// `Handle::current` use.
+    static CURRENT: RefCell<Option<Handle>> = const { RefCell::new(None) };
+}
+
+/// The error returned by `Spawner::try_spawn`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The ready queue is at capacity.
+    QueueFull,
+    /// The executor has been shut down.
+    Shutdown,
+}
+
+/// Default number of queued tasks above which `try_spawn` fails.
+const MAX_QUEUED_TASKS: usize = 10_000;
+
+pub fn new_executor_and_spawner() -> (Executor, Spawner) {
+    new_executor_and_spawner_with_capacity(MAX_QUEUED_TASKS)
+}
+
+/// Like `new_executor_and_spawner`, but with a custom bound on the number of
+/// queued tasks accepted by `try_spawn` and `spawn_with_backpressure`.
+pub fn new_executor_and_spawner_with_capacity(capacity: usize) -> (Executor, Spawner) {
+    let shared = Arc::new(Shared {
+        ready_queue: ReadyQueue::new(capacity),
+        tasks: Mutex::new(BTreeMap::new()),
+        next_id: AtomicUsize::new(0),
+        metrics: OnceLock::new(),
+        task_dump: AtomicBool::new(false),
+        created: Instant::now(),
+    });
+    shared.ready_queue.acquire_handle();
+    let spawner = Spawner {
+        shared: shared.clone(),
+    };
+    let executor = Executor {
+        shared,
+        panic_policy: PanicPolicy::default(),
+    };
+    (executor, spawner)
+}
+
+/// Spawn `future` onto the current executor, returning a `JoinHandle` that
+/// resolves to its output. See `Spawner::spawn`.
+///
+/// # Panics
+///
+/// Panics if called outside of a task, or of `Executor::enter`.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match Handle::try_current() {
+        Some(handle) => handle.spawn(future),
+        None => panic!(
+            "`spawn` called outside of an executor; call it from a task, or after `Executor::enter`"
+        ),
+    }
+}
+
+impl Spawner {
+    /// Spawn `future` onto the executor, returning a `JoinHandle` that
+    /// resolves to its output.
+    ///
+    /// This ignores the queue's capacity. Use `try_spawn` or
+    /// `spawn_with_backpressure` to respect it. After the executor has been
+    /// shut down, the future is dropped and the `JoinHandle` resolves to
+    /// `JoinError::Cancelled`.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = self.new_task(future, None, Priority::Normal);
+        let _ = self.shared.ready_queue.push_spawned(task, Priority::Normal);
+        handle
+    }
+
+    /// Spawn `future` onto the executor unless the ready queue is full or
+    /// the executor has been shut down.
+    pub fn try_spawn<F>(&self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = self.new_task(future, None, Priority::Normal);
+        match self.shared.ready_queue.try_push_spawned(task, Priority::Normal) {
+            Ok(()) => Ok(handle),
+            Err(Rejected::Full) => Err(SpawnError::QueueFull),
+            Err(Rejected::Closed) => Err(SpawnError::Shutdown),
+        }
+    }
+
+    /// Spawn `future` onto the executor, waiting until the ready queue has
+    /// room for it.
+    pub async fn spawn_with_backpressure<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = self.new_task(future, None, Priority::Normal);
+        let mut task = Some(task);
+        let ready_queue = &self.shared.ready_queue;
+        let _ = poll_fn(|cx| {
+            ready_queue.poll_push_spawned(cx, || task.take().unwrap(), Priority::Normal)
+        })
+        .await;
+        handle
+    }
+
+    /// Get a builder to spawn a task with extra options, such as a name or
+    /// a priority.
+    pub fn builder(&self) -> TaskBuilder<'_> {
+        TaskBuilder {
+            spawner: self,
+            name: None,
+            priority: Priority::Normal,
+        }
+    }
+
+    fn new_task<F>(
+        &self,
+        future: F,
+        name: Option<String>,
+        priority: Priority,
+    ) -> (Arc<Task>, JoinHandle<F::Output>)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (future, handle) = with_join_handle(future);
+        self.shared.ready_queue.acquire_handle();
+        let task = Arc::new(Task {
+            future: TaskCell::new(future.boxed()),
+            id: self.shared.next_id.fetch_add(1, Ordering::SeqCst),
+            name,
+            priority,
+            last_polled: AtomicU64::new(0),
+            last_woken_by: Mutex::new(None),
+            panic_reporter: handle.panic_reporter(),
+            metrics: self.shared.metrics.get().map(Recorder::new_task),
+            shared: self.shared.clone(),
+        });
+        self.shared.tasks.lock().unwrap().insert(task.id, Arc::downgrade(&task));
+        handle.bind(Arc::downgrade(&task) as Weak<dyn Schedule>);
+        (task, handle)
+    }
+}
+
+impl TaskBuilder<'_> {
+    /// Name the task, for `Executor::dump_tasks`.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Set the task's priority. Defaults to `Priority::Normal`.
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Spawn the task. See `Spawner::spawn`.
+    pub fn spawn<F>(self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = self.spawner.new_task(future, self.name, self.priority);
+        let _ = self.spawner.shared.ready_queue.push_spawned(task, self.priority);
+        handle
+    }
+}
+
+impl Handle {
+    /// Get a handle to the current executor: the one polling the calling
+    /// task, or the one entered with `Executor::enter`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a task, or of `Executor::enter`.
+    pub fn current() -> Handle {
+        Handle::try_current().expect(
+            "`Handle::current` called outside of an executor; call it from a task, or after `Executor::enter`",
+        )
+    }
+
+    /// Like `current`, but returns `None` outside of an executor.
+    pub fn try_current() -> Option<Handle> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// Spawn `future` onto the executor. See `Spawner::spawn`.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawner().spawn(future)
+    }
+
+    /// Get a `Spawner` for the executor, e.g. to spawn with options, or to
+    /// keep `Executor::run` going.
+    pub fn spawner(&self) -> Spawner {
+        self.shared.ready_queue.acquire_handle();
+        Spawner {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for EnterGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+impl Clone for Spawner {
+    fn clone(&self) -> Self {
+        self.shared.ready_queue.acquire_handle();
+        Spawner {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Spawner {
+    fn drop(&mut self) {
+        self.shared.ready_queue.release_handle();
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.shared.tasks.lock().unwrap().remove(&self.id);
+        self.shared.ready_queue.release_handle();
+    }
+}
+
+impl Task {
+    /// Place a woken task back onto the ready queue.
+    fn requeue(self: &Arc<Self>) {
+        if let (Some(recorder), Some(counters)) = (self.shared.metrics.get(), &self.metrics) {
+            recorder.queued(counters);
+        }
+        self.shared.ready_queue.push(self.clone(), self.priority);
+    }
+
+    /// Record what is waking the task, for `Executor::dump_tasks`.
+    fn record_wake(&self) {
+        let source = match CURRENT_TASK.try_with(Cell::get) {
+            Ok(Some((shared, id))) if ptr::eq(shared, &*self.shared) => WakeSource::Task(id),
+            // `try_with`, as a thread-local destructor may wake the task
+            // after this thread's own locals are gone.
+            _ => match CURRENT_THREAD.try_with(Thread::clone) {
+                Ok(thread) => WakeSource::Thread(thread),
+                Err(_) => return,
+            },
+        };
+        *self.last_woken_by.lock().unwrap() = Some(source);
+    }
+}
+
+impl Wake for Task {
+    fn wake_by_ref(self: &Arc<Self>) {
+        if let Some(counters) = &self.metrics {
+            counters.woken();
+        }
+        if self.shared.task_dump.load(Ordering::Relaxed) {
+            self.record_wake();
+        }
+        if self.future.wake() {
+            self.requeue();
+        }
+    }
+}
+
+impl Schedule for Task {
+    fn schedule(self: Arc<Self>) {
+        Wake::wake(self)
+    }
+}
+
+/// Wakes `Executor::run_until` when its main future can make progress.
+struct MainWaker {
+    woken: AtomicBool,
+    shared: Arc<Shared>,
+}
+
+impl Wake for MainWaker {
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        self.shared.ready_queue.notify();
+    }
+}
+
+impl Executor {
+    /// Make this the current executor on this thread until the returned
+    /// guard is dropped, so that `spawn` and `Handle::current` can be used
+    /// outside of its tasks. The executor's own methods enter it while they
+    /// poll tasks.
+    pub fn enter(&self) -> EnterGuard {
+        let previous = CURRENT.with(|current| current.borrow_mut().replace(self.handle()));
+        EnterGuard {
+            previous,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Get a handle which can spawn tasks onto this executor from any
+    /// thread.
+    pub fn handle(&self) -> Handle {
+        Handle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Choose what happens when a task panics. Defaults to
+    /// `PanicPolicy::Log`.
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        self.panic_policy = policy;
+    }
+
+    /// Start collecting metrics, which `metrics` returns. Per-task metrics
+    /// are only collected for tasks spawned from now on.
+    pub fn enable_metrics(&self) {
+        self.shared.metrics.get_or_init(Recorder::new);
+    }
+
+    /// Take a snapshot of the executor's metrics, or return `None` if they
+    /// haven't been enabled.
+    pub fn metrics(&self) -> Option<ExecutorMetrics> {
+        let recorder = self.shared.metrics.get()?;
+        let tasks: Vec<Arc<Task>> = self
+            .shared
+            .tasks
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let counters = tasks
+            .iter()
+            .filter_map(|task| Some((task.id, task.metrics.as_ref()?)));
+        Some(recorder.snapshot(self.shared.ready_queue.len(), counters))
+    }
+
+    /// Start recording when each task is polled and what wakes it, which
+    /// `dump_tasks` reports.
+    pub fn enable_task_dump(&self) {
+        self.shared.task_dump.store(true, Ordering::Relaxed);
+    }
+
+    /// List every live task with its name, state, time since it was last
+    /// polled and what last woke it. This can be called from another thread
+    /// while the executor is running, e.g. to find out why it hangs.
+    ///
+    /// The time since the last poll and the last wake are only known for
+    /// polls and wakes since `enable_task_dump` was called.
+    pub fn dump_tasks(&self) -> TaskDump {
+        let tasks: Vec<Arc<Task>> = self
+            .shared
+            .tasks
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let now = self.shared.created.elapsed();
+        let tasks = tasks
+            .iter()
+            .filter_map(|task| {
+                let state = match task.future.phase() {
+                    Phase::Scheduled => TaskState::Queued,
+                    Phase::Running => TaskState::Running,
+                    Phase::Idle => TaskState::Idle,
+                    Phase::Complete => return None,
+                };
+                let since_last_poll = match task.last_polled.load(Ordering::Relaxed) {
+                    0 => None,
+                    polled => Some(now.saturating_sub(Duration::from_nanos(polled - 1))),
+                };
+                Some(TaskInfo {
+                    id: task.id,
+                    name: task.name.clone(),
+                    state,
+                    since_last_poll,
+                    last_woken_by: task.last_woken_by.lock().unwrap().clone(),
+                })
+            })
+            .collect();
+        TaskDump { tasks }
+    }
+
+    /// Run tasks until every `Spawner` and every task has been dropped.
+    pub fn run(&self) {
+        let _enter = self.enter();
+        while let Some(task) = self.wait(|| self.shared.ready_queue.pop()) {
+            self.poll_task(task);
+        }
+    }
+
+    /// Run tasks until none of them is ready to make progress, without
+    /// waiting for wakeups from other threads.
+    pub fn run_until_stalled(&self) {
+        let _enter = self.enter();
+        while let Some(task) = self.shared.ready_queue.try_pop() {
+            self.poll_task(task);
+        }
+    }
+
+    /// Run tasks until `future` completes, and return its output. `future`
+    /// runs on the current thread, so it doesn't need to be `Send`. Tasks
+    /// which are still running when it completes stay on the executor.
+    pub fn run_until<F: Future>(&self, future: F) -> F::Output {
+        let _enter = self.enter();
+        let mut future = pin!(future);
+        let main_waker = Arc::new(MainWaker {
+            woken: AtomicBool::new(true),
+            shared: self.shared.clone(),
+        });
+        loop {
+            if main_waker.woken.swap(false, Ordering::SeqCst) {
+                let waker = waker_ref(&main_waker);
+                let context = &mut Context::from_waker(&waker);
+                if let Poll::Ready(output) = future.as_mut().poll(context) {
+                    return output;
+                }
+            }
+            let ready = || main_waker.woken.load(Ordering::SeqCst);
+            if let Some(task) = self.wait(|| self.shared.ready_queue.pop_until(ready)) {
+                self.poll_task(task);
+            }
+        }
+    }
+
+    /// Shut the executor down: new spawns are rejected, the tasks which are
+    /// already queued are polled one last time, and then every remaining
+    /// task is dropped in the order it was spawned. Their `JoinHandle`s
+    /// resolve to `JoinError::Cancelled`.
+    pub fn shutdown(&self) {
+        let _enter = self.enter();
+        for task in self.shared.ready_queue.close() {
+            self.poll_task(task);
+        }
+        self.cancel_tasks();
+    }
+
+    /// Drop the future of every task, in the order they were spawned.
+    fn cancel_tasks(&self) {
+        let tasks: Vec<Arc<Task>> = self
+            .shared
+            .tasks
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for task in tasks {
+            task.future.cancel();
+        }
+    }
+
+    /// Wait for a task with `pop`, counting the time as idle.
+    fn wait(&self, pop: impl FnOnce() -> Option<Arc<Task>>) -> Option<Arc<Task>> {
+        let Some(recorder) = self.shared.metrics.get() else {
+            return pop();
+        };
+        let started = Instant::now();
+        let task = pop();
+        recorder.idle(started.elapsed());
+        task
+    }
+
+    fn poll_task(&self, task: Arc<Task>) {
+        let recorder = self.shared.metrics.get();
+        let started = recorder.map(|recorder| recorder.poll_started(task.metrics.as_ref()));
+        if self.shared.task_dump.load(Ordering::Relaxed) {
+            let polled = self.shared.created.elapsed().as_nanos() as u64 + 1;
+            task.last_polled.store(polled, Ordering::Relaxed);
+        }
+        let previous = CURRENT_TASK.with(|current| current.replace(Some((&*self.shared, task.id))));
+        let waker = waker_ref(&task);
+        let context = &mut Context::from_waker(&waker);
+        let mut propagated = None;
+        let mut exhausted = false;
+        let outcome = task.future.run(|future| {
+            // Catching the panic here, rather than letting it unwind through
+            // `run`, keeps the other tasks running and leaves the task in a
+            // state where its future can be dropped.
+            let poll = || coop::with_budget(|| future.as_mut().poll(context));
+            match catch_unwind(AssertUnwindSafe(poll)) {
+                Ok((poll, used_up)) => {
+                    exhausted = used_up;
+                    poll.is_ready()
+                }
+                Err(payload) => {
+                    propagated = self.handle_panic(&task, payload);
+                    true
+                }
+            }
+        });
+        CURRENT_TASK.with(|current| current.set(previous));
+        if let (Some(recorder), Some(started)) = (recorder, started) {
+            recorder.poll_finished(task.metrics.as_ref(), started);
+        }
+        // A task which used up its budget goes to the back of the queue,
+        // whether or not anything woke it.
+        let requeue = match outcome {
+            RunOutcome::Rescheduled => true,
+            RunOutcome::Idle => exhausted && task.future.wake(),
+            RunOutcome::Complete | RunOutcome::Skipped => false,
+        };
+        if requeue {
+            task.requeue();
+        }
+        if let Some(payload) = propagated {
+            resume_unwind(payload);
+        }
+    }
+
+    /// Apply the panic policy to a task which panicked. The task's future is
+    /// dropped afterwards, and then the returned payload, if any, is resumed.
+    fn handle_panic(
+        &self,
+        task: &Task,
+        payload: Box<dyn Any + Send + 'static>,
+    ) -> Option<Box<dyn Any + Send + 'static>> {
+        let message = panic_message(&*payload).unwrap_or("Box<dyn Any>");
+        match self.panic_policy {
+            PanicPolicy::Log => {
+                eprintln!("task {} panicked: {message}", task.id);
+                task.panic_reporter.report(payload);
+                None
+            }
+            PanicPolicy::Abort => {
+                eprintln!("task {} panicked, aborting: {message}", task.id);
+                std::process::abort();
+            }
+            PanicPolicy::Propagate => Some(payload),
+        }
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        // Queued tasks hold on to `Shared`, which holds the ready queue, so
+        // they would leak along with their futures. Nothing can run them any
+        // more, so they are cancelled, like the tasks which aren't queued.
+        let queued = self.shared.ready_queue.close();
+        self.cancel_tasks();
+        drop(queued);
+    }
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpawnError::QueueFull => write!(f, "too many tasks queued"),
+            SpawnError::Shutdown => write!(f, "executor has been shut down"),
+        }
+    }
+}
+
+impl Error for SpawnError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block_on, coop::yield_now};
+    use futures::{channel::oneshot, future::poll_fn};
+    use std::{
+        sync::atomic::AtomicUsize,
+        task::{Poll, Waker},
+        thread,
+        time::Duration,
+    };
+    use timer_future::{MockClock, TimerFuture};
+
+    #[test]
+    fn join_handle_resolves_to_output() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let handle = spawner.spawn(async { 1 + 2 });
+        drop(spawner);
+        executor.run();
+        assert_eq!(block_on(handle).unwrap(), 3);
+    }
+
+    #[test]
+    fn await_join_handle_from_another_task() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let inner_spawner = spawner.clone();
+        let outer = spawner.spawn(async move {
+            // The inner task only completes after the outer task has started
+            // waiting on it, so the outer task must be woken by the handle.
+            let inner = inner_spawner.spawn(async {
+                TimerFuture::new(Duration::from_millis(10)).await;
+                "inner"
+            });
+            let output = inner.await.unwrap();
+            format!("outer saw {output}")
+        });
+        drop(spawner);
+        executor.run();
+        assert_eq!(block_on(outer).unwrap(), "outer saw inner");
+    }
+
+    #[test]
+    fn mock_timer_advanced_from_another_thread() {
+        const ROUNDS: usize = 10_000;
+        let clock = MockClock::new();
+        // The number of timers created so far. The advancer spins on it, so
+        // that each advance lands as close as possible to a timer's first
+        // poll.
+        let created = Arc::new(AtomicUsize::new(0));
+        let (advancer_clock, advanced) = (clock.clone(), created.clone());
+        let advancer = thread::spawn(move || {
+            for round in 1..=ROUNDS {
+                while advanced.load(Ordering::SeqCst) < round {
+                    std::hint::spin_loop();
+                }
+                advancer_clock.advance(Duration::from_secs(1));
+            }
+        });
+        let (executor, spawner) = new_executor_and_spawner();
+        spawner.spawn(async move {
+            for _ in 0..ROUNDS {
+                let guard = clock.enter();
+                let timer = TimerFuture::new(Duration::from_secs(1));
+                drop(guard);
+                created.fetch_add(1, Ordering::SeqCst);
+                // However the advance interleaves with the first poll, the
+                // task must be woken, as nothing else advances the clock.
+                timer.await;
+            }
+        });
+        drop(spawner);
+        executor.run();
+        advancer.join().unwrap();
+    }
+
+    #[test]
+    fn dropping_join_handle_detaches_task() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let ran = Arc::new(AtomicBool::new(false));
+        let task_ran = ran.clone();
+        drop(spawner.spawn(async move {
+            TimerFuture::new(Duration::from_millis(10)).await;
+            task_ran.store(true, Ordering::SeqCst);
+        }));
+        drop(spawner);
+        executor.run();
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    /// Sets its flag when dropped.
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn abort_drops_pending_timer() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+        let flag = DropFlag(dropped.clone());
+        let task_finished = finished.clone();
+        let sleeper = spawner.spawn(async move {
+            let _flag = flag;
+            TimerFuture::new(Duration::from_millis(100)).await;
+            task_finished.store(true, Ordering::SeqCst);
+        });
+        let abort_handle = sleeper.abort_handle();
+        let observed = Arc::new(AtomicBool::new(false));
+        let task_observed = observed.clone();
+        let task_dropped = dropped.clone();
+        spawner.spawn(async move {
+            abort_handle.abort();
+            let result = sleeper.await;
+            assert!(result.unwrap_err().is_cancelled());
+            // The `JoinHandle` only resolves once the future has been
+            // dropped, together with the `TimerFuture` it was waiting on.
+            assert!(task_dropped.load(Ordering::SeqCst));
+            task_observed.store(true, Ordering::SeqCst);
+        });
+        drop(spawner);
+        executor.run();
+        assert!(observed.load(Ordering::SeqCst));
+        assert!(!finished.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn abort_before_first_poll() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let polled = Arc::new(AtomicBool::new(false));
+        let task_polled = polled.clone();
+        let handle = spawner.spawn(async move {
+            task_polled.store(true, Ordering::SeqCst);
+        });
+        handle.abort();
+        drop(spawner);
+        executor.run();
+        assert!(!polled.load(Ordering::SeqCst));
+        assert!(block_on(handle).unwrap_err().is_cancelled());
+    }
+
+    #[test]
+    fn abort_task_that_never_wakes() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = DropFlag(dropped.clone());
+        let handle = spawner.spawn(async move {
+            let _flag = flag;
+            // The task keeps its own waker alive, so without `abort` it would
+            // stay pending forever and `run` would never return.
+            let mut own_waker = None;
+            poll_fn(|cx| {
+                own_waker = Some(cx.waker().clone());
+                Poll::<()>::Pending
+            })
+            .await;
+        });
+        let inner_spawner = spawner.clone();
+        drop(spawner);
+        inner_spawner.spawn(async move { handle.abort() });
+        drop(inner_spawner);
+        executor.run();
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn abort_after_completion_has_no_effect() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let handle = spawner.spawn(async { "done" });
+        drop(spawner);
+        executor.run();
+        handle.abort();
+        assert_eq!(block_on(handle).unwrap(), "done");
+    }
+
+    #[test]
+    fn try_spawn_fails_when_queue_is_full() {
+        let (executor, spawner) = new_executor_and_spawner_with_capacity(2);
+        let first = spawner.try_spawn(async { 1 }).unwrap();
+        let second = spawner.try_spawn(async { 2 }).unwrap();
+        assert_eq!(spawner.try_spawn(async { 3 }).err(), Some(SpawnError::QueueFull));
+        drop(spawner);
+        executor.run();
+        assert_eq!(block_on(first).unwrap(), 1);
+        assert_eq!(block_on(second).unwrap(), 2);
+    }
+
+    #[test]
+    fn spawn_with_backpressure_waits_for_capacity() {
+        let (executor, spawner) = new_executor_and_spawner_with_capacity(4);
+        let completed = Arc::new(AtomicUsize::new(0));
+        let inner_spawner = spawner.clone();
+        let task_completed = completed.clone();
+        spawner.spawn(async move {
+            let mut spawned = 0;
+            for _ in 0..100 {
+                let completed = task_completed.clone();
+                inner_spawner
+                    .spawn_with_backpressure(async move {
+                        completed.fetch_add(1, Ordering::SeqCst);
+                    })
+                    .await;
+                // The producer is never more than `capacity` tasks ahead.
+                spawned += 1;
+                assert!(spawned - task_completed.load(Ordering::SeqCst) <= 4);
+            }
+        });
+        drop(spawner);
+        executor.run();
+        assert_eq!(completed.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn million_wakeups() {
+        const TASKS: usize = 100;
+        const WAKERS: usize = 4;
+        const WAKES_PER_THREAD: usize = 250_000;
+
+        // A small capacity shows that wakes are not limited by it.
+        let (executor, spawner) = new_executor_and_spawner_with_capacity(10);
+        let wakers = Arc::new(Mutex::new(Vec::new()));
+        let done = Arc::new(AtomicBool::new(false));
+        let polls = Arc::new(AtomicUsize::new(0));
+        for _ in 0..TASKS {
+            let wakers = wakers.clone();
+            let done = done.clone();
+            let polls = polls.clone();
+            spawner.spawn(poll_fn(move |cx| {
+                if polls.fetch_add(1, Ordering::SeqCst) < TASKS {
+                    wakers.lock().unwrap().push(cx.waker().clone());
+                }
+                if done.load(Ordering::SeqCst) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }));
+        }
+        drop(spawner);
+        let executor = thread::spawn(move || executor.run());
+
+        while wakers.lock().unwrap().len() < TASKS {
+            thread::yield_now();
+        }
+        let wakers: Arc<Vec<Waker>> = Arc::new(wakers.lock().unwrap().drain(..).collect());
+        let threads: Vec<_> = (0..WAKERS)
+            .map(|n| {
+                let wakers = wakers.clone();
+                thread::spawn(move || {
+                    for i in 0..WAKES_PER_THREAD {
+                        wakers[(n + i) % TASKS].wake_by_ref();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        done.store(true, Ordering::SeqCst);
+        wakers.iter().for_each(Waker::wake_by_ref);
+        drop(wakers);
+        executor.join().unwrap();
+    }
+
+    #[test]
+    fn task_references_are_released() {
+        let (executor, spawner) = new_executor_and_spawner();
+        for _ in 0..10 {
+            let mut woken = false;
+            spawner.spawn(poll_fn(move |cx| {
+                if woken {
+                    return Poll::Ready(());
+                }
+                woken = true;
+                // Exercise every vtable entry, from this and another thread.
+                let waker = cx.waker().clone();
+                waker.clone().wake_by_ref();
+                drop(waker.clone());
+                thread::spawn(move || waker.wake());
+                Poll::Pending
+            }));
+        }
+        drop(spawner);
+        executor.run();
+        // Every task holds a reference to `shared`, so a leaked task
+        // reference would keep it above one.
+        assert!(executor.shared.tasks.lock().unwrap().is_empty());
+        assert_eq!(Arc::strong_count(&executor.shared), 1);
+    }
+
+    /// Stays pending forever, keeping its task alive by storing its waker in
+    /// `wakers`.
+    fn parked(wakers: Arc<Mutex<Vec<Waker>>>) -> impl Future<Output = ()> {
+        poll_fn(move |cx| {
+            wakers.lock().unwrap().push(cx.waker().clone());
+            Poll::Pending
+        })
+    }
+
+    #[test]
+    fn run_until_stalled_does_not_wait_for_timers() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let progress = Arc::new(AtomicUsize::new(0));
+        let task_progress = progress.clone();
+        spawner.spawn(async move {
+            task_progress.fetch_add(1, Ordering::SeqCst);
+            TimerFuture::new(Duration::from_millis(50)).await;
+            task_progress.fetch_add(1, Ordering::SeqCst);
+        });
+        executor.run_until_stalled();
+        assert_eq!(progress.load(Ordering::SeqCst), 1);
+
+        drop(spawner);
+        executor.run();
+        assert_eq!(progress.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn run_until_returns_main_output() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let background_done = Arc::new(AtomicBool::new(false));
+        let task_done = background_done.clone();
+        spawner.spawn(async move {
+            TimerFuture::new(Duration::from_millis(50)).await;
+            task_done.store(true, Ordering::SeqCst);
+        });
+        let answer = spawner.spawn(async {
+            TimerFuture::new(Duration::from_millis(10)).await;
+            42
+        });
+        // The main future doesn't have to be `Send`.
+        let not_send = std::rc::Rc::new(1);
+        let output = executor.run_until(async move { answer.await.unwrap() + *not_send });
+        assert_eq!(output, 43);
+        assert!(!background_done.load(Ordering::SeqCst));
+
+        // The background task is still on the executor.
+        drop(spawner);
+        executor.run();
+        assert!(background_done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn shutdown_drains_queue_and_drops_remaining_tasks() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let wakers = Arc::new(Mutex::new(Vec::new()));
+
+        /// Logs its name when dropped.
+        struct LogOnDrop(&'static str, Arc<Mutex<Vec<&'static str>>>);
+
+        impl Drop for LogOnDrop {
+            fn drop(&mut self) {
+                self.1.lock().unwrap().push(self.0);
+            }
+        }
+
+        let first = LogOnDrop("first dropped", log.clone());
+        let first_wakers = wakers.clone();
+        let first = spawner.spawn(async move {
+            let _first = first;
+            parked(first_wakers).await;
+        });
+        let second = LogOnDrop("second dropped", log.clone());
+        let second_wakers = wakers.clone();
+        let second = spawner.spawn(async move {
+            let _second = second;
+            parked(second_wakers).await;
+        });
+        executor.run_until_stalled();
+
+        // This task is still queued when the executor shuts down, so it gets
+        // polled once more before the pending tasks are dropped.
+        let queued_log = log.clone();
+        spawner.spawn(async move {
+            queued_log.lock().unwrap().push("queued ran");
+        });
+        executor.shutdown();
+        assert_eq!(*log.lock().unwrap(), ["queued ran", "first dropped", "second dropped"]);
+        assert!(block_on(first).unwrap_err().is_cancelled());
+        assert!(block_on(second).unwrap_err().is_cancelled());
+
+        let late = spawner.spawn(async {});
+        assert!(block_on(late).unwrap_err().is_cancelled());
+        assert_eq!(spawner.try_spawn(async {}).err(), Some(SpawnError::Shutdown));
+        // `run` returns straight away even though a `Spawner` is still alive.
+        executor.run();
+    }
+
+    #[test]
+    fn dropping_executor_cancels_tasks() {
+        let (executor, spawner) = new_executor_and_spawner();
+
+        /// Sets its flag when dropped.
+        struct SetOnDrop(Arc<AtomicBool>);
+
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let polled_dropped = Arc::new(AtomicBool::new(false));
+        let polled = SetOnDrop(polled_dropped.clone());
+        let wakers = Arc::new(Mutex::new(Vec::new()));
+        let polled = spawner.spawn(async move {
+            let _polled = polled;
+            parked(wakers).await;
+        });
+        executor.run_until_stalled();
+        let queued_dropped = Arc::new(AtomicBool::new(false));
+        let queued = SetOnDrop(queued_dropped.clone());
+        let queued = spawner.spawn(async move {
+            let _queued = queued;
+        });
+
+        drop((executor, spawner));
+        assert!(polled_dropped.load(Ordering::SeqCst));
+        assert!(queued_dropped.load(Ordering::SeqCst));
+        assert!(block_on(polled).unwrap_err().is_cancelled());
+        assert!(block_on(queued).unwrap_err().is_cancelled());
+    }
+
+    #[test]
+    fn panicking_task_does_not_stop_others() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let panicked = spawner.spawn(async {
+            TimerFuture::new(Duration::from_millis(10)).await;
+            panic!("boom");
+        });
+        let survivor = spawner.spawn(async {
+            TimerFuture::new(Duration::from_millis(20)).await;
+            "still here"
+        });
+        // Awaiting the handle of a panicked task from another task works too.
+        let observer = spawner.spawn(async move {
+            let error = panicked.await.unwrap_err();
+            assert_eq!(error.to_string(), "task panicked: boom");
+            *error.into_panic().downcast::<&str>().unwrap()
+        });
+        drop(spawner);
+        executor.run();
+        assert_eq!(block_on(survivor).unwrap(), "still here");
+        assert_eq!(block_on(observer).unwrap(), "boom");
+    }
+
+    #[test]
+    fn task_can_be_polled_after_another_panics() {
+        // A panic must not leave the executor unable to poll other tasks,
+        // nor leave the panicked task stuck in the running state.
+        let (executor, spawner) = new_executor_and_spawner();
+        let handle = spawner.spawn(async {
+            panic!("first poll");
+        });
+        let counter = Arc::new(AtomicUsize::new(0));
+        let task_counter = counter.clone();
+        spawner.spawn(async move {
+            for _ in 0..3 {
+                task_counter.fetch_add(1, Ordering::SeqCst);
+                TimerFuture::new(Duration::from_millis(1)).await;
+            }
+        });
+        drop(spawner);
+        executor.run();
+        assert!(block_on(handle).unwrap_err().is_panic());
+        assert_eq!(counter.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn propagate_policy_resumes_panic() {
+        let (mut executor, spawner) = new_executor_and_spawner();
+        executor.set_panic_policy(PanicPolicy::Propagate);
+        let handle = spawner.spawn(async {
+            panic!("propagated");
+        });
+        let payload = catch_unwind(AssertUnwindSafe(|| executor.run_until_stalled())).unwrap_err();
+        assert_eq!(panic_message(&*payload), Some("propagated"));
+        assert!(block_on(handle).unwrap_err().is_cancelled());
+
+        // The executor can keep going after the panic.
+        let after = spawner.spawn(async { 1 });
+        drop(spawner);
+        executor.run();
+        assert_eq!(block_on(after).unwrap(), 1);
+    }
+
+    #[test]
+    fn metrics_disabled_by_default() {
+        let (executor, _spawner) = new_executor_and_spawner();
+        assert!(executor.metrics().is_none());
+    }
+
+    #[test]
+    fn metrics_count_polls_wakes_and_queue_time() {
+        let (executor, spawner) = new_executor_and_spawner();
+        executor.enable_metrics();
+        let wakers = Arc::new(Mutex::new(Vec::new()));
+        let task_wakers = wakers.clone();
+        spawner.spawn(async move {
+            for _ in 0..3 {
+                // Yield, waking the task twice while it is being polled.
+                let mut yielded = false;
+                poll_fn(|cx| {
+                    if yielded {
+                        return Poll::Ready(());
+                    }
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                })
+                .await;
+            }
+            parked(task_wakers).await;
+        });
+        spawner.spawn(async {});
+        assert_eq!(executor.metrics().unwrap().queue_depth, 2);
+        thread::sleep(Duration::from_millis(10));
+        executor.run_until_stalled();
+
+        let metrics = executor.metrics().unwrap();
+        assert_eq!(metrics.queue_depth, 0);
+        // The second task has completed, so only the first one is listed.
+        let [task] = &metrics.tasks[..] else {
+            panic!("expected one task, got {:?}", metrics.tasks);
+        };
+        assert_eq!((task.id, task.polls, task.wakes), (0, 4, 6));
+        assert!(task.queued_time >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn metrics_measure_busy_and_idle_time() {
+        let (executor, spawner) = new_executor_and_spawner();
+        executor.enable_metrics();
+        spawner.spawn(async {
+            // Blocks the executor, which counts as busy time.
+            thread::sleep(Duration::from_millis(20));
+            TimerFuture::new(Duration::from_millis(20)).await;
+        });
+        drop(spawner);
+        executor.run();
+        let metrics = executor.metrics().unwrap();
+        assert!(metrics.busy_time >= Duration::from_millis(20));
+        assert!(metrics.idle_time >= Duration::from_millis(10));
+        let ratio = metrics.busy_ratio();
+        assert!(ratio > 0.0 && ratio < 1.0, "busy ratio {ratio}");
+    }
+
+    #[test]
+    fn dump_tasks_while_running() {
+        use futures::channel::oneshot;
+
+        let (executor, spawner) = new_executor_and_spawner();
+        executor.enable_task_dump();
+        let (notify_waiter, waiter_notified) = oneshot::channel::<()>();
+        let (notify_notifier, notifier_notified) = oneshot::channel::<()>();
+        let (stop, stopped) = oneshot::channel::<()>();
+        spawner.builder().name("waiter").spawn(async move {
+            let _ = waiter_notified.await;
+        });
+        spawner.builder().name("notifier").spawn(async move {
+            let _ = notifier_notified.await;
+            let _ = notify_waiter.send(());
+            let _ = stopped.await;
+        });
+        spawner.spawn(async {});
+        drop(spawner);
+
+        let dump = executor.dump_tasks();
+        let states: Vec<_> = dump.tasks.iter().map(|task| task.state).collect();
+        assert_eq!(states, [TaskState::Queued; 3]);
+        assert!(dump.tasks.iter().all(|task| task.since_last_poll.is_none()));
+
+        thread::scope(|scope| {
+            scope.spawn(|| executor.run());
+            let wait_for = |done: &dyn Fn(&TaskDump) -> bool| loop {
+                let dump = executor.dump_tasks();
+                if done(&dump) {
+                    return dump;
+                }
+                thread::sleep(Duration::from_millis(1));
+            };
+
+            // The unnamed task completes, and the others wait.
+            let dump = wait_for(&|dump| {
+                dump.tasks.len() == 2 && dump.tasks.iter().all(|task| task.state == TaskState::Idle)
+            });
+            assert_eq!(dump.tasks[0].name.as_deref(), Some("waiter"));
+            assert!(dump.tasks[0].since_last_poll.is_some());
+            assert!(dump.tasks[0].last_woken_by.is_none());
+
+            // Woken by this thread, the notifier wakes the waiter.
+            notify_notifier.send(()).unwrap();
+            let dump = wait_for(&|dump| dump.tasks.len() == 1 && dump.tasks[0].state == TaskState::Idle);
+            let notifier = &dump.tasks[0];
+            assert_eq!((notifier.id, notifier.name.as_deref()), (1, Some("notifier")));
+            match &notifier.last_woken_by {
+                Some(WakeSource::Thread(thread)) => assert_eq!(thread.id(), thread::current().id()),
+                other => panic!("woken by {other:?}"),
+            }
+            let report = dump.to_string();
+            assert!(report.starts_with("1 live tasks\n  task 1 \"notifier\": idle, last polled "));
+            stop.send(()).unwrap();
+        });
+    }
+
+    #[test]
+    fn wake_from_another_task_is_recorded() {
+        let (executor, spawner) = new_executor_and_spawner();
+        executor.enable_task_dump();
+        let wakers = Arc::new(Mutex::new(Vec::new()));
+        let task_wakers = wakers.clone();
+        spawner.builder().name("parked").spawn(parked(task_wakers));
+        executor.run_until_stalled();
+        spawner.builder().name("waking").spawn(async move {
+            wakers.lock().unwrap().pop().unwrap().wake();
+        });
+        executor.run_until_stalled();
+        let dump = executor.dump_tasks();
+        assert!(matches!(dump.tasks[0].last_woken_by, Some(WakeSource::Task(1))));
+        assert!(dump.to_string().contains("last woken by task 1\n"));
+    }
+
+    #[test]
+    fn dump_without_tracking() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let wakers = Arc::new(Mutex::new(Vec::new()));
+        spawner.builder().name("parked").spawn(parked(wakers.clone()));
+        executor.run_until_stalled();
+        wakers.lock().unwrap().pop().unwrap().wake();
+        let dump = executor.dump_tasks();
+        assert_eq!(dump.tasks[0].state, TaskState::Queued);
+        assert!(dump.tasks[0].since_last_poll.is_none());
+        assert!(dump.tasks[0].last_woken_by.is_none());
+    }
+
+    /// Saturate an executor with tasks which are always ready, and return
+    /// the most polls of other tasks seen between waking a task of the given
+    /// priority and polling it.
+    fn wake_latency_under_load(priority: Priority) -> usize {
+        const BUSY_TASKS: usize = 100;
+        let (executor, spawner) = new_executor_and_spawner();
+        let polls = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        // The probe leaves a sender here, and the next busy task to be
+        // polled wakes the probe through it.
+        let wake_probe = Arc::new(Mutex::new(None::<oneshot::Sender<usize>>));
+        for _ in 0..BUSY_TASKS {
+            let (polls, stop, wake_probe) = (polls.clone(), stop.clone(), wake_probe.clone());
+            spawner.spawn(async move {
+                while !stop.load(Ordering::SeqCst) {
+                    let polled = polls.fetch_add(1, Ordering::SeqCst) + 1;
+                    if let Some(sender) = wake_probe.lock().unwrap().take() {
+                        sender.send(polled).unwrap();
+                    }
+                    yield_now().await;
+                }
+            });
+        }
+        let probe = spawner.builder().priority(priority).spawn(async move {
+            let mut worst = 0;
+            for _ in 0..10 {
+                let (sender, receiver) = oneshot::channel();
+                *wake_probe.lock().unwrap() = Some(sender);
+                let polls_at_wake = receiver.await.unwrap();
+                worst = worst.max(polls.load(Ordering::SeqCst) - polls_at_wake);
+            }
+            stop.store(true, Ordering::SeqCst);
+            worst
+        });
+        drop(spawner);
+        executor.run();
+        block_on(probe).unwrap()
+    }
+
+    #[test]
+    fn high_priority_wake_latency_under_load() {
+        // A woken high-priority task is polled as soon as the task which
+        // woke it yields, while a normal one waits behind every busy task.
+        assert_eq!(wake_latency_under_load(Priority::High), 0);
+        assert!(wake_latency_under_load(Priority::Normal) >= 99);
+    }
+
+    #[test]
+    fn low_priority_task_is_not_starved() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let done = Arc::new(AtomicBool::new(false));
+        for _ in 0..10 {
+            let done = done.clone();
+            spawner.builder().priority(Priority::High).spawn(async move {
+                // Always ready, so without aging the low-priority task would
+                // never be polled and this would never end.
+                while !done.load(Ordering::SeqCst) {
+                    yield_now().await;
+                }
+            });
+        }
+        let low = spawner.builder().priority(Priority::Low).spawn(async move {
+            for _ in 0..3 {
+                yield_now().await;
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        drop(spawner);
+        executor.run();
+        block_on(low).unwrap();
+    }
+
+    #[test]
+    fn spawn_onto_current_executor() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let outer = spawner.spawn(async {
+            let inner = spawn(async { "inner" });
+            inner.await.unwrap()
+        });
+        drop(spawner);
+        executor.run();
+        assert_eq!(block_on(outer).unwrap(), "inner");
+    }
+
+    #[test]
+    fn handle_spawns_from_another_thread() {
+        let (executor, _spawner) = new_executor_and_spawner();
+        let output = executor.run_until(async {
+            let handle = Handle::current();
+            let (sender, receiver) = oneshot::channel();
+            thread::spawn(move || {
+                // Not an executor thread, so only the handle knows where to
+                // spawn.
+                assert!(Handle::try_current().is_none());
+                sender.send(handle.spawn(async { 7 })).ok().unwrap();
+            });
+            receiver.await.unwrap().await.unwrap()
+        });
+        assert_eq!(output, 7);
+    }
+
+    #[test]
+    fn enter_outside_of_tasks() {
+        let (executor, _spawner) = new_executor_and_spawner();
+        let guard = executor.enter();
+        let handle = spawn(async { 1 });
+        drop(guard);
+        assert!(Handle::try_current().is_none());
+        executor.run_until_stalled();
+        assert_eq!(block_on(handle).unwrap(), 1);
+
+        // Entering nests, and each guard restores the executor before it.
+        let (other, _other_spawner) = new_executor_and_spawner();
+        let outer = executor.enter();
+        let inner = other.enter();
+        spawn(async {});
+        drop(inner);
+        assert_eq!(other.shared.ready_queue.len(), 1);
+        assert!(Arc::ptr_eq(&Handle::current().shared, &executor.shared));
+        drop(outer);
+    }
+
+    #[test]
+    #[should_panic(expected = "`spawn` called outside of an executor")]
+    fn spawn_outside_of_executor_panics() {
+        spawn(async {});
+    }
+
+    #[test]
+    #[should_panic(expected = "`Handle::current` called outside of an executor")]
+    fn handle_outside_of_executor_panics() {
+        Handle::current();
+    }
+}
@@ -0,0 +1,275 @@
+use futures::ready;
+use pin_project_lite::pin_project;
+use std::{
+    any::Any,
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Arc, Mutex, OnceLock, Weak},
+    task::{Context, Poll, Waker},
+};
+
+use crate::coop;
+
+/// A handle to a spawned task which resolves to the task's output.
+///
+/// Dropping a `JoinHandle` detaches the task: it keeps running, but its
+/// output is dropped as soon as it completes.
+pub struct JoinHandle<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+    abort: AbortHandle,
+}
+
+/// A handle which can cancel a spawned task without awaiting its output.
+#[derive(Clone)]
+pub struct AbortHandle {
+    control: Arc<TaskControl>,
+}
+
+/// Lets an executor hand the payload of a panic caught while polling a task
+/// to the task's `JoinHandle`.
+pub(crate) struct PanicReporter {
+    control: Arc<TaskControl>,
+}
+
+/// The reason a task did not run to completion.
+#[derive(Debug)]
+pub enum JoinError {
+    /// The task was aborted, or dropped by its executor before completing.
+    Cancelled,
+    /// The task panicked. Holds the panic payload.
+    Panicked(Box<dyn Any + Send + 'static>),
+}
+
+/// Shared state between a task and its `JoinHandle`.
+enum Slot<T> {
+    /// The task is still running. Holds the waker of the task awaiting the
+    /// `JoinHandle`, if any.
+    Running(Option<Waker>),
+    /// The task finished, and its result has not been taken yet.
+    Finished(Result<T, JoinError>),
+    /// The result was returned by `JoinHandle::poll`.
+    Taken,
+}
+
+/// State shared between a task, its `JoinHandle` and its `AbortHandle`s.
+struct TaskControl {
+    aborted: AtomicBool,
+    /// The task to schedule so that it notices it has been aborted. This is
+    /// a `Weak` reference so that handles don't keep finished tasks alive.
+    task: OnceLock<Weak<dyn Schedule>>,
+    /// The payload of a panic caught by the executor, which the `JoinHandle`
+    /// resolves to once the task's future has been dropped.
+    panic: Mutex<Option<Box<dyn Any + Send + 'static>>>,
+}
+
+/// A task that can be placed back onto its executor's ready queue.
+pub(crate) trait Schedule: Send + Sync {
+    fn schedule(self: Arc<Self>);
+}
+
+pin_project! {
+    /// A spawned future, wrapped so that its output is sent to its
+    /// `JoinHandle` and so that it can be cancelled through an `AbortHandle`.
+    pub(crate) struct Joinable<F: Future> {
+        // Declared before `completion` so that it is dropped first: the
+        // `JoinHandle` only reports cancellation once the future is gone.
+        #[pin]
+        future: F,
+        completion: Completion<F::Output>,
+        abort: AbortHandle,
+    }
+}
+
+/// Wrap `future` so that its output is sent to the returned `JoinHandle`.
+pub(crate) fn with_join_handle<F>(future: F) -> (Joinable<F>, JoinHandle<F::Output>)
+where
+    F: Future,
+{
+    let slot = Arc::new(Mutex::new(Slot::Running(None)));
+    let abort = AbortHandle {
+        control: Arc::new(TaskControl {
+            aborted: AtomicBool::new(false),
+            task: OnceLock::new(),
+            panic: Mutex::new(None),
+        }),
+    };
+    let joinable = Joinable {
+        future,
+        completion: Completion {
+            slot: slot.clone(),
+            control: abort.control.clone(),
+        },
+        abort: abort.clone(),
+    };
+    (joinable, JoinHandle { slot, abort })
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.project();
+        // Completing straight away makes the executor drop the task, and
+        // with it the aborted future.
+        if this.abort.is_aborted() {
+            return Poll::Ready(());
+        }
+        // If `future` panics, unwinding leaves `completion` in place, so the
+        // executor can still report the panic before dropping the task.
+        this.future.poll(cx).map(|output| this.completion.finish(Ok(output)))
+    }
+}
+
+/// Reports the task's result to its `JoinHandle`. If the task is dropped
+/// before finishing, the `JoinHandle` resolves to `JoinError::Panicked` if
+/// a panic was reported, and to `JoinError::Cancelled` otherwise.
+struct Completion<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+    control: Arc<TaskControl>,
+}
+
+impl<T> Completion<T> {
+    fn finish(&self, result: Result<T, JoinError>) {
+        let mut slot = self.slot.lock().unwrap();
+        if let Slot::Running(waker) = &mut *slot {
+            let waker = waker.take();
+            *slot = Slot::Finished(result);
+            drop(slot);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        let error = match self.control.panic.lock().unwrap().take() {
+            Some(payload) => JoinError::Panicked(payload),
+            None => JoinError::Cancelled,
+        };
+        self.finish(Err(error));
+    }
+}
+
+impl<T> JoinHandle<T> {
+    /// Tell the handle which task it belongs to, so that aborting it can
+    /// reschedule the task. Executors call this before the task first runs.
+    pub(crate) fn bind(&self, task: Weak<dyn Schedule>) {
+        let _ = self.abort.control.task.set(task);
+    }
+
+    /// Get a `PanicReporter` for the task, for executors which catch panics.
+    pub(crate) fn panic_reporter(&self) -> PanicReporter {
+        PanicReporter {
+            control: self.abort.control.clone(),
+        }
+    }
+
+    /// Abort the task. See `AbortHandle::abort`.
+    pub fn abort(&self) {
+        self.abort.abort();
+    }
+
+    /// Get a handle that can abort the task without owning its output.
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        ready!(coop::poll_proceed(cx));
+        let mut slot = self.slot.lock().unwrap();
+        match &mut *slot {
+            Slot::Running(waker) => {
+                // Only clone the waker if the handle moved to another task
+                // since it was last polled.
+                if !waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+                    *waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            Slot::Finished(_) => match std::mem::replace(&mut *slot, Slot::Taken) {
+                Slot::Finished(result) => Poll::Ready(result),
+                _ => unreachable!(),
+            },
+            Slot::Taken => panic!("`JoinHandle` polled after completion"),
+        }
+    }
+}
+
+impl AbortHandle {
+    /// Abort the task. Its future is dropped the next time the executor
+    /// schedules it, and its `JoinHandle` resolves to `JoinError::Cancelled`.
+    /// Aborting a task which already finished has no effect.
+    pub fn abort(&self) {
+        self.control.aborted.store(true, Ordering::SeqCst);
+        if let Some(task) = self.control.task.get().and_then(Weak::upgrade) {
+            task.schedule();
+        }
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.control.aborted.load(Ordering::SeqCst)
+    }
+}
+
+impl PanicReporter {
+    /// Record that the task panicked. The executor must then drop the
+    /// task's future, which resolves the `JoinHandle` to
+    /// `JoinError::Panicked(payload)`.
+    pub(crate) fn report(&self, payload: Box<dyn Any + Send + 'static>) {
+        *self.control.panic.lock().unwrap() = Some(payload);
+    }
+}
+
+/// Get the message of a panic payload, if it has one.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> Option<&str> {
+    payload
+        .downcast_ref::<&'static str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+}
+
+impl JoinError {
+    /// Whether the task was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, JoinError::Cancelled)
+    }
+
+    /// Whether the task panicked.
+    pub fn is_panic(&self) -> bool {
+        matches!(self, JoinError::Panicked(_))
+    }
+
+    /// Get the payload of the task's panic, e.g. to resume it with
+    /// `std::panic::resume_unwind`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the task did not panic.
+    pub fn into_panic(self) -> Box<dyn Any + Send + 'static> {
+        match self {
+            JoinError::Panicked(payload) => payload,
+            JoinError::Cancelled => panic!("`JoinError::into_panic` called on a cancelled task"),
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+            JoinError::Panicked(payload) => match panic_message(&**payload) {
+                Some(message) => write!(f, "task panicked: {message}"),
+                None => write!(f, "task panicked"),
+            },
+        }
+    }
+}
+
+impl Error for JoinError {}
@@ -1,5 +1,35 @@
-#![cfg(test)]
+mod block_on;
+mod blocking;
+pub mod coop;
+mod dump;
+mod executor;
+mod join_handle;
+pub mod local;
+mod metrics;
+mod raw_waker;
+mod ready_queue;
+mod scope;
+pub mod sim;
+pub mod slab;
+mod task_cell;
+pub mod work_stealing;
 
+pub use block_on::{block_on, block_on_timeout};
+pub use blocking::{spawn_blocking, BlockingPool};
+pub use dump::{TaskDump, TaskInfo, TaskState, WakeSource};
+pub use executor::{
+    new_executor_and_spawner, new_executor_and_spawner_with_capacity, spawn, EnterGuard, Executor,
+    Handle, PanicPolicy, SpawnError, Spawner, TaskBuilder,
+};
+pub use join_handle::{AbortHandle, JoinError, JoinHandle};
+pub use metrics::{ExecutorMetrics, TaskMetrics};
+pub use ready_queue::Priority;
+pub use scope::{scope, Scope, ScopeFuture};
+pub use timer_future::Elapsed;
+
+// The executor built step by step in the "Applied: Build an Executor" chapter.
+#[cfg(test)]
+mod chapter {
 // ANCHOR: imports
 use futures::{
     future::{BoxFuture, FutureExt},
@@ -132,3 +162,4 @@ fn main() {
 fn run_main() {
     main()
 }
+}
@@ -0,0 +1,366 @@
+//! A single-threaded executor for futures which are not `Send`.
+//!
+//! `Spawner::spawn` requires `Send` futures, so it rejects the `Rc` example
+//! from the "`Send` Approximation" chapter:
+//!
+//! ```compile_fail,E0277
+//! # use std::rc::Rc;
+//! # use example_02_04_executor::new_executor_and_spawner;
+//! #[derive(Default)]
+//! struct NotSend(Rc<()>);
+//!
+//! async fn bar() {}
+//! async fn foo() {
+//!     let x = NotSend::default();
+//!     bar().await;
+//! }
+//!
+//! let (executor, spawner) = new_executor_and_spawner();
+//! spawner.spawn(foo());
+//! ```
+//!
+//! The same goes for the work-stealing executor:
+//!
+//! ```compile_fail,E0277
+//! # use std::rc::Rc;
+//! # use example_02_04_executor::work_stealing::new_executor_and_spawner;
+//! # #[derive(Default)]
+//! # struct NotSend(Rc<()>);
+//! # async fn bar() {}
+//! # async fn foo() {
+//! #     let x = NotSend::default();
+//! #     bar().await;
+//! # }
+//! let (executor, spawner) = new_executor_and_spawner(4);
+//! spawner.spawn(foo());
+//! ```
+//!
+//! A `LocalSpawner` never sends its tasks to another thread, so it accepts
+//! them:
+//!
+//! ```
+//! # use std::rc::Rc;
+//! # use example_02_04_executor::local::new_local_executor_and_spawner;
+//! # #[derive(Default)]
+//! # struct NotSend(Rc<()>);
+//! # async fn bar() {}
+//! # async fn foo() {
+//! #     let x = NotSend::default();
+//! #     bar().await;
+//! # }
+//! let (executor, spawner) = new_local_executor_and_spawner();
+//! spawner.spawn_local(foo());
+//! drop(spawner);
+//! executor.run();
+//! ```
+
+use futures::future::{FutureExt, LocalBoxFuture};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    future::Future,
+    rc::Rc,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Arc, Mutex, Weak},
+    task::Context,
+};
+
+use crate::join_handle::{with_join_handle, JoinHandle, Schedule};
+use crate::raw_waker::{waker_ref, Wake};
+use crate::ready_queue::{Priority, ReadyQueue};
+
+/// Executor for `!Send` futures, which runs every task on the thread that
+/// calls `run`.
+pub struct LocalExecutor {
+    state: Rc<LocalState>,
+}
+
+/// `LocalSpawner` spawns new futures onto a `LocalExecutor`. It can't be
+/// sent to other threads.
+#[derive(Clone)]
+pub struct LocalSpawner {
+    state: Rc<LocalState>,
+    /// Counts this spawner as a handle on the ready queue.
+    _handle: Rc<SpawnerHandle>,
+}
+
+struct LocalState {
+    /// The futures of all live tasks, keyed by task id, or `None` while the
+    /// task is being polled. Wakers only carry the id, since the futures
+    /// themselves must stay on this thread.
+    tasks: RefCell<HashMap<usize, Option<LocalBoxFuture<'static, ()>>>>,
+    next_id: Cell<usize>,
+    ready_queue: Arc<ReadyQueue<Arc<TaskWaker>>>,
+    orphans: Arc<Mutex<Vec<usize>>>,
+}
+
+/// The thread-safe part of a task, from which its `Waker` is built. It is
+/// kept alive by the task's wakers and by the ready queue, and counts as a
+/// handle on the queue until then.
+struct TaskWaker {
+    id: usize,
+    /// Whether the task is already in the ready queue.
+    scheduled: AtomicBool,
+    ready_queue: Arc<ReadyQueue<Arc<TaskWaker>>>,
+    /// Ids of the tasks whose `TaskWaker` has been dropped. Nothing can wake
+    /// them any more, so the executor drops their futures.
+    orphans: Arc<Mutex<Vec<usize>>>,
+}
+
+struct SpawnerHandle {
+    ready_queue: Arc<ReadyQueue<Arc<TaskWaker>>>,
+}
+
+pub fn new_local_executor_and_spawner() -> (LocalExecutor, LocalSpawner) {
+    let ready_queue = Arc::new(ReadyQueue::new(usize::MAX));
+    ready_queue.acquire_handle();
+    let state = Rc::new(LocalState {
+        tasks: RefCell::new(HashMap::new()),
+        next_id: Cell::new(0),
+        ready_queue: ready_queue.clone(),
+        orphans: Arc::new(Mutex::new(Vec::new())),
+    });
+    let spawner = LocalSpawner {
+        state: state.clone(),
+        _handle: Rc::new(SpawnerHandle { ready_queue }),
+    };
+    (LocalExecutor { state }, spawner)
+}
+
+impl LocalSpawner {
+    /// Spawn `future` onto the executor, returning a `JoinHandle` that
+    /// resolves to its output. Neither needs to be `Send`.
+    pub fn spawn_local<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (future, handle) = with_join_handle(future);
+        let id = self.state.next_id.get();
+        self.state.next_id.set(id + 1);
+        let waker = Arc::new(TaskWaker {
+            id,
+            scheduled: AtomicBool::new(true),
+            ready_queue: self.state.ready_queue.clone(),
+            orphans: self.state.orphans.clone(),
+        });
+        handle.bind(Arc::downgrade(&waker) as Weak<dyn Schedule>);
+        self.state.ready_queue.acquire_handle();
+        self.state.tasks.borrow_mut().insert(id, Some(future.boxed_local()));
+        self.state.ready_queue.push(waker, Priority::Normal);
+        handle
+    }
+}
+
+impl Drop for SpawnerHandle {
+    fn drop(&mut self) {
+        self.ready_queue.release_handle();
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::SeqCst) {
+            self.ready_queue.push(self.clone(), Priority::Normal);
+        }
+    }
+}
+
+impl Schedule for TaskWaker {
+    fn schedule(self: Arc<Self>) {
+        Wake::wake(self)
+    }
+}
+
+impl Drop for TaskWaker {
+    fn drop(&mut self) {
+        self.orphans.lock().unwrap().push(self.id);
+        // `run` may be waiting, and needs to drop the task's future, which
+        // may hold a `LocalSpawner`.
+        self.ready_queue.notify();
+        self.ready_queue.release_handle();
+    }
+}
+
+impl LocalExecutor {
+    /// Run tasks until every `LocalSpawner` has been dropped and every task
+    /// has completed. A task which nothing can wake any more, because its
+    /// wakers have all been dropped, is dropped too, and its `JoinHandle`
+    /// resolves to `JoinError::Cancelled`.
+    pub fn run(&self) {
+        let ready_queue = &self.state.ready_queue;
+        let orphaned = || !self.state.orphans.lock().unwrap().is_empty();
+        loop {
+            self.drop_orphans();
+            match ready_queue.pop_until(|| ready_queue.is_released() || orphaned()) {
+                Some(waker) => self.poll_task(waker),
+                None if ready_queue.is_released() => break,
+                None => {}
+            }
+        }
+        self.drop_orphans();
+    }
+
+    fn poll_task(&self, waker: Arc<TaskWaker>) {
+        // Take the future out of the map while it is polled, so that it can
+        // spawn more tasks without the map being borrowed.
+        waker.scheduled.store(false, Ordering::SeqCst);
+        let future = self.state.tasks.borrow_mut().get_mut(&waker.id).and_then(Option::take);
+        let Some(mut future) = future else {
+            return;
+        };
+        let waker_ref = waker_ref(&waker);
+        let context = &mut Context::from_waker(&waker_ref);
+        if future.as_mut().poll(context).is_pending() {
+            if let Some(task) = self.state.tasks.borrow_mut().get_mut(&waker.id) {
+                *task = Some(future);
+            }
+        } else {
+            self.state.tasks.borrow_mut().remove(&waker.id);
+        }
+    }
+
+    /// Drop the futures of the tasks which can't be woken any more.
+    fn drop_orphans(&self) {
+        let orphans = std::mem::take(&mut *self.state.orphans.lock().unwrap());
+        let futures: Vec<_> = {
+            let mut tasks = self.state.tasks.borrow_mut();
+            orphans.iter().filter_map(|id| tasks.remove(id)).collect()
+        };
+        // Outside the borrow, as dropping a future may spawn or drop tasks.
+        drop(futures);
+    }
+}
+
+impl Drop for LocalExecutor {
+    fn drop(&mut self) {
+        // Pending tasks may hold a `LocalSpawner`, which would keep the state
+        // and with it the tasks alive forever. Queued tasks hold the ready
+        // queue, which holds them in turn.
+        let queued = self.state.ready_queue.close();
+        let tasks = std::mem::take(&mut *self.state.tasks.borrow_mut());
+        drop(tasks);
+        drop(queued);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_on;
+    use std::time::Duration;
+    use timer_future::TimerFuture;
+
+    #[allow(dead_code)]
+    #[derive(Default)]
+    struct NotSend(Rc<()>);
+
+    async fn bar() {}
+
+    // The example from the "`Send` Approximation" chapter, which holds an
+    // `Rc` across an `.await`.
+    async fn foo() {
+        let _x = NotSend::default();
+        bar().await;
+    }
+
+    #[test]
+    fn runs_send_approximation_example() {
+        let (executor, spawner) = new_local_executor_and_spawner();
+        let handle = spawner.spawn_local(foo());
+        drop(spawner);
+        executor.run();
+        assert!(block_on(handle).is_ok());
+    }
+
+    #[test]
+    fn rc_shared_between_tasks() {
+        let (executor, spawner) = new_local_executor_and_spawner();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let inner_spawner = spawner.clone();
+        let task_log = log.clone();
+        spawner.spawn_local(async move {
+            task_log.borrow_mut().push("outer start");
+            let inner_log = task_log.clone();
+            let inner = inner_spawner.spawn_local(async move {
+                // Woken from the timer thread, which only sees the task id.
+                TimerFuture::new(Duration::from_millis(10)).await;
+                inner_log.borrow_mut().push("inner");
+                Rc::new(5)
+            });
+            let value = inner.await.unwrap();
+            task_log.borrow_mut().push("outer end");
+            *value
+        });
+        drop(spawner);
+        executor.run();
+        assert_eq!(*log.borrow(), ["outer start", "inner", "outer end"]);
+    }
+
+    #[test]
+    fn abort_local_task() {
+        let (executor, spawner) = new_local_executor_and_spawner();
+        let handle = spawner.spawn_local(async {
+            TimerFuture::new(Duration::from_millis(10)).await;
+            Rc::new(())
+        });
+        handle.abort();
+        drop(spawner);
+        executor.run();
+        assert!(block_on(handle).unwrap_err().is_cancelled());
+    }
+
+    #[test]
+    fn unwakeable_task_is_dropped() {
+        let (executor, spawner) = new_local_executor_and_spawner();
+        let dropped = Rc::new(Cell::new(false));
+
+        /// Sets its flag when dropped.
+        struct SetOnDrop(Rc<Cell<bool>>);
+
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        let on_drop = SetOnDrop(dropped.clone());
+        let inner_spawner = spawner.clone();
+        let handle = spawner.spawn_local(async move {
+            // Holding a spawner doesn't keep `run` going once nothing can
+            // wake the task.
+            let _spawner = inner_spawner;
+            let _on_drop = on_drop;
+            futures::future::pending::<()>().await;
+        });
+        drop(spawner);
+        executor.run();
+        assert!(dropped.get());
+        assert!(block_on(handle).unwrap_err().is_cancelled());
+    }
+
+    #[test]
+    fn dropping_executor_drops_tasks() {
+        let (executor, spawner) = new_local_executor_and_spawner();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let wakers = Arc::new(Mutex::new(Vec::new()));
+        let (task_log, task_wakers) = (log.clone(), wakers.clone());
+        let task_spawner = spawner.clone();
+        let parked = spawner.spawn_local(async move {
+            let _spawner = task_spawner;
+            futures::future::poll_fn(|cx| {
+                task_wakers.lock().unwrap().push(cx.waker().clone());
+                std::task::Poll::<()>::Pending
+            })
+            .await;
+            task_log.borrow_mut().push("parked ran");
+        });
+        let queued = spawner.spawn_local(async {});
+        drop((executor, spawner));
+        assert!(log.borrow().is_empty());
+        assert!(block_on(parked).unwrap_err().is_cancelled());
+        assert!(block_on(queued).unwrap_err().is_cancelled());
+        // The stored waker outlives the executor, and does nothing.
+        wakers.lock().unwrap().drain(..).for_each(std::task::Waker::wake);
+    }
+}
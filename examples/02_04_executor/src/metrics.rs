@@ -0,0 +1,157 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// A snapshot of an `Executor`'s metrics, taken by `Executor::metrics`.
+#[derive(Clone, Debug)]
+pub struct ExecutorMetrics {
+    /// Number of tasks in the ready queue.
+    pub queue_depth: usize,
+    /// Time spent polling tasks since metrics were enabled.
+    pub busy_time: Duration,
+    /// Time spent waiting for a task to become ready since metrics were
+    /// enabled.
+    pub idle_time: Duration,
+    /// Every live task spawned after metrics were enabled, in the order they
+    /// were spawned.
+    pub tasks: Vec<TaskMetrics>,
+}
+
+/// Metrics for a single task.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskMetrics {
+    /// Identifies the task. Ids are assigned in the order tasks are spawned.
+    pub id: usize,
+    /// Number of times the task was polled.
+    pub polls: u64,
+    /// Number of times the task was woken, including wakes while it was
+    /// already queued.
+    pub wakes: u64,
+    /// Total time spent polling the task.
+    pub poll_time: Duration,
+    /// Total time the task spent in the ready queue before being polled.
+    pub queued_time: Duration,
+}
+
+impl ExecutorMetrics {
+    /// The fraction of time the executor spent polling tasks, rather than
+    /// waiting for them, between 0 and 1.
+    pub fn busy_ratio(&self) -> f64 {
+        let total = self.busy_time + self.idle_time;
+        if total.is_zero() {
+            return 0.0;
+        }
+        self.busy_time.as_secs_f64() / total.as_secs_f64()
+    }
+}
+
+/// The executor-wide metrics, which only exist once they have been enabled.
+pub(crate) struct Recorder {
+    /// Times stored in atomics are nanoseconds since `start`.
+    start: Instant,
+    busy_nanos: AtomicU64,
+    idle_nanos: AtomicU64,
+}
+
+/// The counters of a single task.
+pub(crate) struct TaskCounters {
+    polls: AtomicU64,
+    wakes: AtomicU64,
+    poll_nanos: AtomicU64,
+    queued_nanos: AtomicU64,
+    /// When the task was last placed on the ready queue.
+    queued_at: AtomicU64,
+}
+
+impl Recorder {
+    pub(crate) fn new() -> Self {
+        Recorder {
+            start: Instant::now(),
+            busy_nanos: AtomicU64::new(0),
+            idle_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        nanos(self.start.elapsed())
+    }
+
+    /// Create the counters for a task which is about to be queued.
+    pub(crate) fn new_task(&self) -> TaskCounters {
+        TaskCounters {
+            polls: AtomicU64::new(0),
+            wakes: AtomicU64::new(0),
+            poll_nanos: AtomicU64::new(0),
+            queued_nanos: AtomicU64::new(0),
+            queued_at: AtomicU64::new(self.now()),
+        }
+    }
+
+    /// Record that `task` was taken off the ready queue and is about to be
+    /// polled. Returns the time the poll started.
+    pub(crate) fn poll_started(&self, task: Option<&TaskCounters>) -> Instant {
+        let now = Instant::now();
+        if let Some(task) = task {
+            let queued_at = task.queued_at.load(Ordering::Relaxed);
+            let waited = nanos(now - self.start).saturating_sub(queued_at);
+            task.queued_nanos.fetch_add(waited, Ordering::Relaxed);
+        }
+        now
+    }
+
+    /// Record that the poll of `task` which started at `started` finished.
+    pub(crate) fn poll_finished(&self, task: Option<&TaskCounters>, started: Instant) {
+        let elapsed = nanos(started.elapsed());
+        self.busy_nanos.fetch_add(elapsed, Ordering::Relaxed);
+        if let Some(task) = task {
+            task.polls.fetch_add(1, Ordering::Relaxed);
+            task.poll_nanos.fetch_add(elapsed, Ordering::Relaxed);
+        }
+    }
+
+    /// Record time spent waiting for a task to become ready.
+    pub(crate) fn idle(&self, elapsed: Duration) {
+        self.idle_nanos.fetch_add(nanos(elapsed), Ordering::Relaxed);
+    }
+
+    /// Record that `task` is about to be placed on the ready queue.
+    pub(crate) fn queued(&self, task: &TaskCounters) {
+        task.queued_at.store(self.now(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot<'a>(
+        &self,
+        queue_depth: usize,
+        tasks: impl Iterator<Item = (usize, &'a TaskCounters)>,
+    ) -> ExecutorMetrics {
+        ExecutorMetrics {
+            queue_depth,
+            busy_time: duration(&self.busy_nanos),
+            idle_time: duration(&self.idle_nanos),
+            tasks: tasks
+                .map(|(id, task)| TaskMetrics {
+                    id,
+                    polls: task.polls.load(Ordering::Relaxed),
+                    wakes: task.wakes.load(Ordering::Relaxed),
+                    poll_time: duration(&task.poll_nanos),
+                    queued_time: duration(&task.queued_nanos),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TaskCounters {
+    pub(crate) fn woken(&self) {
+        self.wakes.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos().try_into().unwrap_or(u64::MAX)
+}
+
+fn duration(nanos: &AtomicU64) -> Duration {
+    Duration::from_nanos(nanos.load(Ordering::Relaxed))
+}
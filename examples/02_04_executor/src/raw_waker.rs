@@ -0,0 +1,178 @@
+//! Building `Waker`s by hand.
+//!
+//! The chapter's executor uses `futures::task::ArcWake` to turn an
+//! `Arc<Task>` into a `Waker`. This module does the same thing directly with
+//! a `RawWakerVTable`: the waker's data pointer is the pointer inside the
+//! `Arc`, and the vtable functions adjust the `Arc`'s reference count.
+//!
+//! The executor polls a task with a `WakerRef`, which borrows the executor's
+//! reference to the task and must therefore never run the `drop` entry.
+//! Every clone of it owns a strong reference of its own, which is released
+//! by `wake` or `drop`.
+
+use std::{
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ops::Deref,
+    sync::Arc,
+    task::{RawWaker, RawWakerVTable, Waker},
+};
+
+/// Something that can be woken through a `Waker` built from an `Arc` of it.
+pub(crate) trait Wake: Send + Sync + 'static {
+    fn wake_by_ref(self: &Arc<Self>);
+
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+}
+
+/// A `Waker` which borrows its task, avoiding a reference count increment
+/// for each poll.
+pub(crate) struct WakerRef<'a> {
+    waker: ManuallyDrop<Waker>,
+    _task: PhantomData<&'a ()>,
+}
+
+impl Deref for WakerRef<'_> {
+    type Target = Waker;
+
+    fn deref(&self) -> &Waker {
+        &self.waker
+    }
+}
+
+/// Get a `Waker` for `task` without touching its reference count.
+pub(crate) fn waker_ref<T: Wake>(task: &Arc<T>) -> WakerRef<'_> {
+    let data = Arc::as_ptr(task).cast::<()>();
+    // SAFETY: `data` points into an `Arc<T>`, which is what the vtable for
+    // `T` expects. The reference is borrowed from `task`, so the `Waker`
+    // must not be dropped. Clones of it get their own through `clone_raw`.
+    let waker = unsafe { Waker::from_raw(RawWaker::new(data, VTable::<T>::VTABLE)) };
+    WakerRef {
+        waker: ManuallyDrop::new(waker),
+        _task: PhantomData,
+    }
+}
+
+/// The vtable of the wakers of a `T`. Every waker refers to it through this
+/// one item: a vtable promoted separately at each use could end up at a
+/// different address in each codegen unit, and `Waker::will_wake` would then
+/// tell equal wakers apart.
+struct VTable<T>(PhantomData<T>);
+
+impl<T: Wake> VTable<T> {
+    const VTABLE: &'static RawWakerVTable =
+        &RawWakerVTable::new(clone_raw::<T>, wake_raw::<T>, wake_by_ref_raw::<T>, drop_raw::<T>);
+}
+
+// The vtable functions below are only ever called with a `data` pointer
+// obtained from `Arc::<T>::as_ptr`, whose reference count includes one
+// reference per owning `Waker`.
+
+unsafe fn clone_raw<T: Wake>(data: *const ()) -> RawWaker {
+    Arc::increment_strong_count(data.cast::<T>());
+    RawWaker::new(data, VTable::<T>::VTABLE)
+}
+
+unsafe fn wake_raw<T: Wake>(data: *const ()) {
+    // Consumes the reference owned by the `Waker`.
+    Wake::wake(Arc::from_raw(data.cast::<T>()));
+}
+
+unsafe fn wake_by_ref_raw<T: Wake>(data: *const ()) {
+    // The reference stays with the `Waker`, so it must not be released here.
+    let task = ManuallyDrop::new(Arc::from_raw(data.cast::<T>()));
+    Wake::wake_by_ref(&task);
+}
+
+unsafe fn drop_raw<T: Wake>(data: *const ()) {
+    drop(Arc::from_raw(data.cast::<T>()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    /// Counts its wakes. Tests hold one reference to it, so a leaked
+    /// reference shows up as a strong count above one, and a double free as
+    /// a count which drops below what the live wakers account for.
+    #[derive(Default)]
+    struct Counter {
+        wakes: AtomicUsize,
+    }
+
+    impl Wake for Counter {
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.wakes.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn references_balance() {
+        let counter = Arc::new(Counter::default());
+        let first = waker_ref(&counter).clone();
+        assert_eq!(Arc::strong_count(&counter), 2);
+
+        let second = first.clone();
+        assert_eq!(Arc::strong_count(&counter), 3);
+        second.wake_by_ref();
+        assert_eq!(Arc::strong_count(&counter), 3);
+        second.wake();
+        assert_eq!(Arc::strong_count(&counter), 2);
+
+        let third = first.clone();
+        third.wake();
+        assert_eq!(Arc::strong_count(&counter), 2);
+        drop(first);
+        assert_eq!(Arc::strong_count(&counter), 1);
+        assert_eq!(counter.wakes.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn waker_ref_borrows_reference() {
+        let counter = Arc::new(Counter::default());
+        let owned = {
+            let borrowed = waker_ref(&counter);
+            borrowed.wake_by_ref();
+            assert_eq!(Arc::strong_count(&counter), 1);
+
+            let owned = borrowed.clone();
+            assert_eq!(Arc::strong_count(&counter), 2);
+            assert!(owned.will_wake(&borrowed));
+            owned
+        };
+        assert_eq!(Arc::strong_count(&counter), 2);
+        owned.wake();
+        assert_eq!(Arc::strong_count(&counter), 1);
+        assert_eq!(counter.wakes.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn wakers_cross_threads() {
+        let counter = Arc::new(Counter::default());
+        let waker = waker_ref(&counter).clone();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let waker = waker.clone();
+                thread::spawn(move || {
+                    for _ in 0..1_000 {
+                        let owned = waker.clone();
+                        owned.wake();
+                        waker.wake_by_ref();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        drop(waker);
+        assert_eq!(Arc::strong_count(&counter), 1);
+        assert_eq!(counter.wakes.load(Ordering::SeqCst), 8_000);
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::{Condvar, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+/// How urgently a task should be polled, set with `TaskBuilder::priority`.
+///
+/// Ready tasks are polled in order of priority, and in the order they were
+/// woken within each priority. So that a steady stream of higher-priority
+/// work can't starve the rest, a ready task is polled next anyway once 32
+/// higher-priority tasks in a row have been polled ahead of it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// Number of times in a row a ready task can be passed over in favour of
+/// higher-priority tasks before it is polled regardless.
+const AGING_LIMIT: u32 = 32;
+
+/// The executor's queue of tasks which are ready to be polled.
+///
+/// Unlike a `sync_channel`, pushing a woken task never blocks and never
+/// fails. The capacity only applies to newly spawned tasks, which can either
+/// be rejected (`try_push_spawned`) or wait for room (`poll_push_spawned`).
+pub(crate) struct ReadyQueue<T> {
+    state: Mutex<QueueState<T>>,
+    condvar: Condvar,
+
+    /// Maximum number of queued tasks before spawning is refused.
+    capacity: usize,
+
+    /// Number of live handles which may still push onto the queue. Once
+    /// this drops to zero, `pop` returns `None` after the queue is drained.
+    handles: AtomicUsize,
+}
+
+struct QueueState<T> {
+    tasks: PriorityQueues<T>,
+    /// Wakers of spawners waiting for the queue to drop below capacity.
+    spawn_waiters: Vec<Waker>,
+    /// Set by `close`, after which nothing new is queued.
+    closed: bool,
+}
+
+/// One FIFO queue per priority, indexed by `Priority as usize`.
+struct PriorityQueues<T> {
+    queues: [VecDeque<T>; 3],
+    /// For each queue, the number of pops in a row which took a task of
+    /// higher priority while it was not empty.
+    passed_over: [u32; 3],
+}
+
+/// Why a newly spawned task was not queued.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Rejected {
+    Full,
+    Closed,
+}
+
+impl<T> ReadyQueue<T> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        ReadyQueue {
+            state: Mutex::new(QueueState {
+                tasks: PriorityQueues::new(),
+                spawn_waiters: Vec::new(),
+                closed: false,
+            }),
+            condvar: Condvar::new(),
+            capacity,
+            handles: AtomicUsize::new(0),
+        }
+    }
+
+    /// Queue a woken task. This always succeeds, although the task is
+    /// dropped if the queue has been closed.
+    pub(crate) fn push(&self, task: T, priority: Priority) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            // Dropping a task may release a handle, which takes the lock.
+            drop(state);
+            drop(task);
+            return;
+        }
+        state.tasks.push(task, priority);
+        drop(state);
+        self.condvar.notify_one();
+    }
+
+    /// Queue a newly spawned task, regardless of the capacity.
+    pub(crate) fn push_spawned(&self, task: T, priority: Priority) -> Result<(), Rejected> {
+        self.push_spawned_if(task, priority, |_| true)
+    }
+
+    /// Queue a newly spawned task, unless the queue is full.
+    pub(crate) fn try_push_spawned(&self, task: T, priority: Priority) -> Result<(), Rejected> {
+        self.push_spawned_if(task, priority, |len| len < self.capacity)
+    }
+
+    /// Queue a newly spawned task once there is room for it. `task` is only
+    /// called once the task is about to be queued.
+    pub(crate) fn poll_push_spawned(
+        &self,
+        cx: &mut Context<'_>,
+        task: impl FnOnce() -> T,
+        priority: Priority,
+    ) -> Poll<Result<(), Rejected>> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Poll::Ready(Err(Rejected::Closed));
+        }
+        if state.tasks.len() >= self.capacity {
+            if !state.spawn_waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                state.spawn_waiters.push(cx.waker().clone());
+            }
+            return Poll::Pending;
+        }
+        state.tasks.push(task(), priority);
+        drop(state);
+        self.condvar.notify_one();
+        Poll::Ready(Ok(()))
+    }
+
+    fn push_spawned_if(
+        &self,
+        task: T,
+        priority: Priority,
+        has_room: impl FnOnce(usize) -> bool,
+    ) -> Result<(), Rejected> {
+        let mut state = self.state.lock().unwrap();
+        let rejected = if state.closed {
+            Rejected::Closed
+        } else if !has_room(state.tasks.len()) {
+            Rejected::Full
+        } else {
+            state.tasks.push(task, priority);
+            drop(state);
+            self.condvar.notify_one();
+            return Ok(());
+        };
+        drop(state);
+        drop(task);
+        Err(rejected)
+    }
+
+    /// Wait for the next task. Returns `None` once the queue is empty and
+    /// either every handle has been released or the queue has been closed.
+    pub(crate) fn pop(&self) -> Option<T> {
+        self.pop_until(|| self.is_released())
+    }
+
+    /// Whether every handle has been released.
+    pub(crate) fn is_released(&self) -> bool {
+        self.handles.load(Ordering::SeqCst) == 0
+    }
+
+    /// Wait for the next task. Returns `None` once the queue is empty and
+    /// `stop` returns `true`, or the queue has been closed. Whoever makes
+    /// `stop` return `true` must call `notify` afterwards.
+    pub(crate) fn pop_until(&self, stop: impl Fn() -> bool) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(task) = state.tasks.pop() {
+                // Every waiting spawner gets a chance to retry: waking only
+                // one could strand the rest if that one is dropped instead.
+                let waiters = std::mem::take(&mut state.spawn_waiters);
+                drop(state);
+                waiters.into_iter().for_each(Waker::wake);
+                return Some(task);
+            }
+            if state.closed || stop() {
+                return None;
+            }
+            state = self.condvar.wait(state).unwrap();
+        }
+    }
+
+    /// Take the next task without waiting for one.
+    pub(crate) fn try_pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        let task = state.tasks.pop()?;
+        let waiters = std::mem::take(&mut state.spawn_waiters);
+        drop(state);
+        waiters.into_iter().for_each(Waker::wake);
+        Some(task)
+    }
+
+    /// Number of queued tasks.
+    pub(crate) fn len(&self) -> usize {
+        self.state.lock().unwrap().tasks.len()
+    }
+
+    /// Wake up a thread blocked in `pop` or `pop_until`.
+    pub(crate) fn notify(&self) {
+        // Taking the lock ensures that the popping thread is either already
+        // waiting (and gets notified) or has not yet checked its condition.
+        let _state = self.state.lock().unwrap();
+        self.condvar.notify_all();
+    }
+
+    /// Stop queueing new tasks and return the ones still queued, highest
+    /// priority first.
+    pub(crate) fn close(&self) -> Vec<T> {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        let tasks = state.tasks.take_all();
+        let waiters = std::mem::take(&mut state.spawn_waiters);
+        drop(state);
+        self.condvar.notify_all();
+        waiters.into_iter().for_each(Waker::wake);
+        tasks
+    }
+
+    pub(crate) fn acquire_handle(&self) {
+        self.handles.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn release_handle(&self) {
+        if self.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.notify();
+        }
+    }
+}
+
+impl<T> PriorityQueues<T> {
+    fn new() -> Self {
+        PriorityQueues {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            passed_over: [0; 3],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    fn push(&mut self, task: T, priority: Priority) {
+        self.queues[priority as usize].push_back(task);
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let starved = (0..3).find(|&i| {
+            !self.queues[i].is_empty() && self.passed_over[i] >= AGING_LIMIT
+        });
+        let chosen = starved.or_else(|| (0..3).find(|&i| !self.queues[i].is_empty()))?;
+        for i in chosen + 1..3 {
+            if !self.queues[i].is_empty() {
+                self.passed_over[i] += 1;
+            }
+        }
+        self.passed_over[chosen] = 0;
+        self.queues[chosen].pop_front()
+    }
+
+    fn take_all(&mut self) -> Vec<T> {
+        self.passed_over = [0; 3];
+        self.queues.iter_mut().flat_map(std::mem::take).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pops_in_priority_order() {
+        let mut queues = PriorityQueues::new();
+        queues.push("low", Priority::Low);
+        queues.push("normal 1", Priority::Normal);
+        queues.push("high", Priority::High);
+        queues.push("normal 2", Priority::Normal);
+        let popped: Vec<_> = std::iter::from_fn(|| queues.pop()).collect();
+        assert_eq!(popped, ["high", "normal 1", "normal 2", "low"]);
+    }
+
+    #[test]
+    fn passed_over_tasks_age() {
+        let mut queues = PriorityQueues::new();
+        queues.push("low", Priority::Low);
+        for _ in 0..AGING_LIMIT * 2 {
+            queues.push("high", Priority::High);
+        }
+        let low_at = std::iter::from_fn(|| queues.pop()).position(|task| task == "low");
+        assert_eq!(low_at, Some(AGING_LIMIT as usize));
+    }
+}
@@ -0,0 +1,364 @@
+//! Structured concurrency: spawning futures which borrow from their caller.
+//!
+//! `Spawner::spawn` requires `'static` futures, because nothing stops the
+//! caller from returning while the task still runs. `scope` lifts this
+//! restriction by keeping its children inside the future it returns: they
+//! are polled concurrently, each with its own waker, whenever the scope
+//! future is polled, and it only completes once all of them have.
+//!
+//! The children are not tasks of the executor, but are multiplexed on the
+//! task which polls the scope, like the futures in a `FuturesUnordered`.
+//! They never run in parallel with each other or with that task, share its
+//! coop budget, and don't show up in the executor's metrics or task dump. A
+//! panic in a child isn't caught, and unwinds out of the parent task's poll,
+//! where the executor's `PanicPolicy` applies to the parent task.
+//!
+//! Since the children never leave the scope future, dropping it early simply
+//! drops them too, and even leaking it with `mem::forget` can't let them run
+//! after the data they borrow is gone.
+
+use futures::future::{BoxFuture, FutureExt, MaybeDone};
+use pin_project_lite::pin_project;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll, Waker},
+};
+
+use crate::join_handle::{with_join_handle, JoinHandle, Schedule};
+use crate::raw_waker::{waker_ref, Wake};
+
+/// Spawns futures which may borrow anything that outlives `'env`. Created by
+/// `scope`, and cheap to clone so that children can spawn more children.
+pub struct Scope<'env> {
+    shared: Arc<ScopeShared<'env>>,
+}
+
+pin_project! {
+    /// The future returned by `scope`.
+    pub struct ScopeFuture<'env, Fut: Future> {
+        #[pin]
+        body: MaybeDone<Fut>,
+        scope: OwnedScope<'env>,
+    }
+}
+
+/// Drops the scope's children along with the `ScopeFuture`.
+struct OwnedScope<'env>(Scope<'env>);
+
+struct ScopeShared<'env> {
+    state: Mutex<ScopeState<'env>>,
+    ready: Arc<ReadyList>,
+}
+
+struct ScopeState<'env> {
+    /// Slots for the children, reused once a child finishes.
+    children: Vec<Child<'env>>,
+    /// Indices of the empty slots in `children`.
+    free: Vec<usize>,
+    /// Set once the `ScopeFuture` has finished or been dropped, after which
+    /// nothing polls new children.
+    closed: bool,
+}
+
+struct Child<'env> {
+    /// `None` while the child is being polled, or once it has finished.
+    future: Option<BoxFuture<'env, ()>>,
+    waker: Arc<ChildWaker>,
+}
+
+/// The children which were woken, and the waker of the task polling the
+/// scope. This doesn't borrow anything, so wakers can outlive the scope.
+struct ReadyList {
+    ready: Mutex<Vec<usize>>,
+    parent: Mutex<Option<Waker>>,
+}
+
+struct ChildWaker {
+    index: usize,
+    /// Whether `index` is already in the ready list.
+    scheduled: AtomicBool,
+    list: Arc<ReadyList>,
+}
+
+impl Wake for ChildWaker {
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::SeqCst) {
+            self.list.ready.lock().unwrap().push(self.index);
+            let parent = self.list.parent.lock().unwrap().clone();
+            if let Some(parent) = parent {
+                parent.wake();
+            }
+        }
+    }
+}
+
+impl Schedule for ChildWaker {
+    fn schedule(self: Arc<Self>) {
+        Wake::wake(self)
+    }
+}
+
+/// Run the future returned by `body` along with every future it spawns on
+/// the `Scope`, which may borrow local data. The returned future completes
+/// with `body`'s output once all of them have finished.
+///
+/// The children run on the task which polls the returned future, rather than
+/// as tasks of their own: see the module docs.
+///
+/// ```
+/// # use example_02_04_executor::{block_on, scope};
+/// let my_string = "foo".to_string();
+/// let my_string = &my_string;
+/// let lengths = block_on(scope(|s| async move {
+///     let one = s.spawn(async { my_string.len() });
+///     let two = s.spawn(async { my_string.len() * 2 });
+///     (one.await.unwrap(), two.await.unwrap())
+/// }));
+/// assert_eq!(lengths, (3, 6));
+/// ```
+///
+/// Dropping the returned future drops the children which haven't finished
+/// yet, whose `JoinHandle`s resolve to `JoinError::Cancelled`.
+pub fn scope<'env, F, Fut>(body: F) -> ScopeFuture<'env, Fut>
+where
+    F: FnOnce(Scope<'env>) -> Fut,
+    Fut: Future,
+{
+    let scope = Scope {
+        shared: Arc::new(ScopeShared {
+            state: Mutex::new(ScopeState {
+                children: Vec::new(),
+                free: Vec::new(),
+                closed: false,
+            }),
+            ready: Arc::new(ReadyList {
+                ready: Mutex::new(Vec::new()),
+                parent: Mutex::new(None),
+            }),
+        }),
+    };
+    ScopeFuture {
+        body: MaybeDone::Future(body(scope.clone())),
+        scope: OwnedScope(scope),
+    }
+}
+
+impl<'env> Scope<'env> {
+    /// Spawn `future` onto the scope, returning a `JoinHandle` that resolves
+    /// to its output. If the scope has already finished, the future is
+    /// dropped and the handle resolves to `JoinError::Cancelled`.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'env,
+        F::Output: Send + 'env,
+    {
+        let (future, handle) = with_join_handle(future);
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            drop(state);
+            drop(future);
+            return handle;
+        }
+        let index = state.free.pop().unwrap_or(state.children.len());
+        let waker = Arc::new(ChildWaker {
+            index,
+            scheduled: AtomicBool::new(false),
+            list: self.shared.ready.clone(),
+        });
+        handle.bind(Arc::downgrade(&waker) as Weak<dyn Schedule>);
+        let child = Child {
+            future: Some(future.boxed()),
+            waker: waker.clone(),
+        };
+        if index == state.children.len() {
+            state.children.push(child);
+        } else {
+            state.children[index] = child;
+        }
+        drop(state);
+        waker.wake();
+        handle
+    }
+
+    /// Poll every child which has been woken. Returns whether any children
+    /// are still running.
+    fn poll_children(&self, cx: &mut Context<'_>) -> bool {
+        let mut parent = self.shared.ready.parent.lock().unwrap();
+        if !parent.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+            *parent = Some(cx.waker().clone());
+        }
+        drop(parent);
+
+        // Only poll the children woken so far: a child which keeps waking
+        // itself would otherwise keep this loop going forever.
+        let ready = std::mem::take(&mut *self.shared.ready.ready.lock().unwrap());
+        for index in ready {
+            let (mut future, waker) = {
+                let mut state = self.shared.state.lock().unwrap();
+                let child = &mut state.children[index];
+                child.waker.scheduled.store(false, Ordering::SeqCst);
+                match child.future.take() {
+                    Some(future) => (future, child.waker.clone()),
+                    None => continue,
+                }
+            };
+            let waker_ref = waker_ref(&waker);
+            let context = &mut Context::from_waker(&waker_ref);
+            let done = future.as_mut().poll(context).is_ready();
+            let mut state = self.shared.state.lock().unwrap();
+            if done {
+                state.free.push(index);
+                drop(state);
+                drop(future);
+            } else {
+                state.children[index].future = Some(future);
+            }
+        }
+        if !self.shared.ready.ready.lock().unwrap().is_empty() {
+            cx.waker().wake_by_ref();
+        }
+        let state = self.shared.state.lock().unwrap();
+        state.free.len() < state.children.len()
+    }
+
+    /// Stop accepting children, and drop the ones which haven't finished.
+    fn close(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        let children = std::mem::take(&mut state.children);
+        state.free.clear();
+        // Dropping a child may drop a `Scope`, so release the lock first.
+        drop(state);
+        drop(children);
+    }
+}
+
+impl Clone for Scope<'_> {
+    fn clone(&self) -> Self {
+        Scope {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<Fut: Future> Future for ScopeFuture<'_, Fut> {
+    type Output = Fut::Output;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Fut::Output> {
+        let mut this = self.project();
+        let body_done = this.body.as_mut().poll(cx).is_ready();
+        let children_running = this.scope.0.poll_children(cx);
+        if !body_done || children_running {
+            return Poll::Pending;
+        }
+        this.scope.0.close();
+        Poll::Ready(this.body.take_output().expect("`ScopeFuture` polled after completion"))
+    }
+}
+
+impl Drop for OwnedScope<'_> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block_on, block_on_timeout, new_executor_and_spawner};
+    use futures::future::pending;
+    use std::{sync::atomic::AtomicUsize, time::Duration};
+    use timer_future::TimerFuture;
+
+    #[test]
+    fn children_borrow_local_data() {
+        // The `blocks()` example from the "`async`/`.await`" chapter, with
+        // the futures spread across tasks.
+        let my_string = "foo".to_string();
+        let printed = Mutex::new(Vec::new());
+        let (my_string, printed) = (&my_string, &printed);
+        block_on(scope(|s| async move {
+            s.spawn(async move { printed.lock().unwrap().push(my_string.clone()) });
+            s.spawn(async move { printed.lock().unwrap().push(my_string.clone()) });
+        }));
+        assert_eq!(*printed.lock().unwrap(), ["foo", "foo"]);
+    }
+
+    #[test]
+    fn waits_for_all_children() {
+        let (executor, _spawner) = new_executor_and_spawner();
+        let finished = AtomicUsize::new(0);
+        let finished = &finished;
+        let body_output = executor.run_until(scope(|s| async move {
+            for millis in [30, 10, 20] {
+                s.spawn(async move {
+                    TimerFuture::new(Duration::from_millis(millis)).await;
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            // The body finishes before any of its children.
+            finished.load(Ordering::SeqCst)
+        }));
+        assert_eq!(body_output, 0);
+        assert_eq!(finished.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn children_spawn_children() {
+        let total = AtomicUsize::new(0);
+        let total = &total;
+        block_on(scope(|s| async move {
+            let inner = s.clone();
+            s.spawn(async move {
+                for n in 1..=3 {
+                    inner.spawn(async move {
+                        TimerFuture::new(Duration::from_millis(1)).await;
+                        total.fetch_add(n, Ordering::SeqCst);
+                    });
+                }
+            });
+        }));
+        assert_eq!(total.load(Ordering::SeqCst), 6);
+    }
+
+    /// Sets its flag when dropped.
+    struct DropFlag<'a>(&'a AtomicBool);
+
+    impl Drop for DropFlag<'_> {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn dropping_scope_cancels_children() {
+        let dropped = AtomicBool::new(false);
+        let dropped = &dropped;
+        let result = block_on_timeout(
+            scope(|s| async move {
+                let child = s.spawn(async move {
+                    let _flag = DropFlag(dropped);
+                    pending::<()>().await;
+                });
+                child.await
+            }),
+            Duration::from_millis(10),
+        );
+        assert!(result.is_err());
+        // The child was dropped along with the scope, while `dropped` was
+        // still borrowed.
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn abort_child() {
+        let outcome = block_on(scope(|s| async move {
+            let child = s.spawn(pending::<()>());
+            child.abort();
+            child.await
+        }));
+        assert!(outcome.unwrap_err().is_cancelled());
+    }
+}
@@ -0,0 +1,440 @@
+//! A deterministic simulation executor for testing concurrent code.
+//!
+//! `Executor` runs tasks in the order they are woken, so a test only ever
+//! sees one interleaving. `Simulation` instead picks the next task out of
+//! all the ready ones with a PRNG. Running a test under many seeds explores
+//! many interleavings, and since the choices only depend on the seed, a
+//! failing seed can be replayed exactly.
+//!
+//! Time is simulated too: tasks run with the simulation's `MockClock`
+//! entered, so `SimHandle::sleep` and every `TimerFuture` a task creates wait
+//! on a virtual clock, which jumps straight to the next deadline once no task
+//! is ready. Sleeps therefore complete instantly, and always in the same
+//! order.
+//!
+//! The simulation is only deterministic if tasks are woken by other tasks or
+//! by its clock. A timer created outside of the simulation, for example,
+//! still runs on the system clock, and wakes its task from the timer thread
+//! at a time the simulation has no control over.
+
+use futures::future::{FutureExt, LocalBoxFuture};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    future::Future,
+    ops::Range,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    rc::Rc,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Arc, Mutex, Weak},
+    task::Context,
+    time::{Duration, Instant},
+};
+use timer_future::{MockClock, TimerFuture};
+
+use crate::coop;
+use crate::join_handle::{panic_message, with_join_handle, JoinHandle, Schedule};
+use crate::raw_waker::{waker_ref, Wake};
+
+/// Runs tasks in an order chosen by a seeded PRNG, on a virtual clock.
+pub struct Simulation {
+    handle: SimHandle,
+}
+
+/// A handle to a `Simulation`, which tasks can use to spawn more tasks and
+/// to sleep.
+#[derive(Clone)]
+pub struct SimHandle {
+    state: Rc<SimState>,
+}
+
+struct SimState {
+    seed: u64,
+    rng: Cell<Rng>,
+    tasks: RefCell<HashMap<usize, SimTask>>,
+    next_id: Cell<usize>,
+    /// Ids of the tasks which are ready to be polled. Shared with the
+    /// wakers, which may be sent to other threads.
+    ready: Arc<Mutex<Vec<usize>>>,
+    /// The id of every task polled so far, in order.
+    schedule: RefCell<Vec<usize>>,
+    /// The virtual clock, which only moves when no task is ready.
+    clock: MockClock,
+    /// The clock's time when the simulation was created.
+    start: Instant,
+}
+
+struct SimTask {
+    /// `None` while the task is being polled.
+    future: Option<LocalBoxFuture<'static, ()>>,
+    waker: Arc<SimWaker>,
+}
+
+struct SimWaker {
+    id: usize,
+    /// Whether the task id is already in `ready`.
+    scheduled: AtomicBool,
+    ready: Arc<Mutex<Vec<usize>>>,
+}
+
+impl Wake for SimWaker {
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::SeqCst) {
+            self.ready.lock().unwrap().push(self.id);
+        }
+    }
+}
+
+impl Schedule for SimWaker {
+    fn schedule(self: Arc<Self>) {
+        Wake::wake(self)
+    }
+}
+
+impl Simulation {
+    /// Create a simulation whose scheduling decisions are determined by
+    /// `seed`.
+    pub fn new(seed: u64) -> Self {
+        let clock = MockClock::new();
+        Simulation {
+            handle: SimHandle {
+                state: Rc::new(SimState {
+                    seed,
+                    rng: Cell::new(Rng(seed)),
+                    tasks: RefCell::new(HashMap::new()),
+                    next_id: Cell::new(0),
+                    ready: Arc::new(Mutex::new(Vec::new())),
+                    schedule: RefCell::new(Vec::new()),
+                    start: clock.now(),
+                    clock,
+                }),
+            },
+        }
+    }
+
+    /// The seed the simulation was created with.
+    pub fn seed(&self) -> u64 {
+        self.handle.state.seed
+    }
+
+    /// Get a handle for tasks to use.
+    pub fn handle(&self) -> SimHandle {
+        self.handle.clone()
+    }
+
+    /// Spawn `future` onto the simulation. See `SimHandle::spawn`.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.handle.spawn(future)
+    }
+
+    /// Time elapsed on the virtual clock.
+    pub fn now(&self) -> Duration {
+        self.handle.now()
+    }
+
+    /// The ids of the tasks polled so far, in the order they were polled.
+    /// Task ids are assigned in the order tasks are spawned, starting at 0.
+    pub fn schedule(&self) -> Vec<usize> {
+        self.handle.state.schedule.borrow().clone()
+    }
+
+    /// Run tasks until none is ready and no timer is pending. Tasks which
+    /// are still waiting for something else at that point stay pending.
+    ///
+    /// A panic in a task is not caught, and unwinds out of `run`.
+    pub fn run(&self) {
+        let state = &self.handle.state;
+        loop {
+            let next = {
+                let mut ready = state.ready.lock().unwrap();
+                if ready.is_empty() {
+                    None
+                } else {
+                    let index = state.pick(ready.len());
+                    Some(ready.swap_remove(index))
+                }
+            };
+            match next {
+                Some(id) => self.poll_task(id),
+                None if state.advance_clock() => {}
+                None => return,
+            }
+        }
+    }
+
+    fn poll_task(&self, id: usize) {
+        let state = &self.handle.state;
+        // Take the future out of the map while it is polled, so that it can
+        // spawn more tasks.
+        let (mut future, waker) = {
+            let mut tasks = state.tasks.borrow_mut();
+            let Some(task) = tasks.get_mut(&id) else {
+                return;
+            };
+            task.waker.scheduled.store(false, Ordering::SeqCst);
+            match task.future.take() {
+                Some(future) => (future, task.waker.clone()),
+                None => return,
+            }
+        };
+        state.schedule.borrow_mut().push(id);
+        // Timers the task creates run on the virtual clock.
+        let _enter = state.clock.enter();
+        let waker_ref = waker_ref(&waker);
+        let context = &mut Context::from_waker(&waker_ref);
+        let (poll, exhausted) = coop::with_budget(|| future.as_mut().poll(context));
+        if poll.is_pending() {
+            if let Some(task) = state.tasks.borrow_mut().get_mut(&id) {
+                task.future = Some(future);
+            }
+            if exhausted {
+                waker.wake_by_ref();
+            }
+        } else {
+            state.tasks.borrow_mut().remove(&id);
+        }
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        // Pending tasks may hold a `SimHandle`, which would keep the state
+        // and with it the tasks alive forever.
+        let tasks = std::mem::take(&mut *self.handle.state.tasks.borrow_mut());
+        drop(tasks);
+    }
+}
+
+impl SimHandle {
+    /// Spawn `future` onto the simulation, returning a `JoinHandle` that
+    /// resolves to its output. Neither needs to be `Send`.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let state = &self.state;
+        let (future, handle) = with_join_handle(future);
+        let id = state.next_id.get();
+        state.next_id.set(id + 1);
+        let waker = Arc::new(SimWaker {
+            id,
+            scheduled: AtomicBool::new(true),
+            ready: state.ready.clone(),
+        });
+        handle.bind(Arc::downgrade(&waker) as Weak<dyn Schedule>);
+        state.tasks.borrow_mut().insert(
+            id,
+            SimTask {
+                future: Some(future.boxed_local()),
+                waker,
+            },
+        );
+        state.ready.lock().unwrap().push(id);
+        handle
+    }
+
+    /// Time elapsed on the virtual clock.
+    pub fn now(&self) -> Duration {
+        self.state.clock.now() - self.state.start
+    }
+
+    /// Wait until `duration` has passed on the virtual clock. Dropping the
+    /// timer cancels the sleep.
+    pub fn sleep(&self, duration: Duration) -> TimerFuture {
+        let _enter = self.state.clock.enter();
+        TimerFuture::new(duration)
+    }
+}
+
+impl SimState {
+    /// Pick an index below `len`.
+    fn pick(&self, len: usize) -> usize {
+        let mut rng = self.rng.get();
+        let index = (rng.next() % len as u64) as usize;
+        self.rng.set(rng);
+        index
+    }
+
+    /// Move the clock to the earliest pending deadline, and wake every timer
+    /// which expires then. Returns `false` if no timer is pending.
+    fn advance_clock(&self) -> bool {
+        let Some(deadline) = self.clock.next_deadline() else {
+            return false;
+        };
+        self.clock
+            .advance(deadline.saturating_duration_since(self.clock.now()));
+        true
+    }
+}
+
+/// The SplitMix64 generator: tiny, fast, and good enough for picking tasks.
+#[derive(Clone, Copy)]
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Run `test` in a fresh simulation for every seed in `seeds`. If it panics,
+/// panic with a message naming the seed, which `Simulation::new` can then
+/// replay.
+pub fn check(seeds: Range<u64>, test: impl Fn(&Simulation)) {
+    for seed in seeds {
+        let simulation = Simulation::new(seed);
+        if let Err(payload) = catch_unwind(AssertUnwindSafe(|| test(&simulation))) {
+            match panic_message(&*payload) {
+                Some(message) => panic!("simulation with seed {seed} failed: {message}"),
+                None => resume_unwind(payload),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block_on, coop::yield_now};
+    use std::collections::HashSet;
+
+    /// Spawns tasks which yield a few times each, and returns the schedule.
+    fn yielding_tasks(simulation: &Simulation) -> Vec<usize> {
+        for _ in 0..4 {
+            simulation.spawn(async {
+                for _ in 0..3 {
+                    yield_now().await;
+                }
+            });
+        }
+        simulation.run();
+        simulation.schedule()
+    }
+
+    #[test]
+    fn same_seed_same_schedule() {
+        let schedules: HashSet<_> = (0..20)
+            .map(|seed| {
+                let schedule = yielding_tasks(&Simulation::new(seed));
+                assert_eq!(schedule, yielding_tasks(&Simulation::new(seed)));
+                schedule
+            })
+            .collect();
+        // Different seeds explore different interleavings.
+        assert!(schedules.len() > 1);
+    }
+
+    #[test]
+    fn sleeps_use_virtual_clock() {
+        let simulation = Simulation::new(0);
+        let handle = simulation.handle();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        for (name, secs) in [("hour", 3600), ("minute", 60), ("second", 1)] {
+            let (handle, log) = (handle.clone(), log.clone());
+            simulation.spawn(async move {
+                handle.sleep(Duration::from_secs(secs)).await;
+                log.borrow_mut().push((name, handle.now()));
+            });
+        }
+        let start = Instant::now();
+        simulation.run();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            *log.borrow(),
+            [
+                ("second", Duration::from_secs(1)),
+                ("minute", Duration::from_secs(60)),
+                ("hour", Duration::from_secs(3600)),
+            ]
+        );
+    }
+
+    #[test]
+    fn dropped_sleep_is_cancelled() {
+        let simulation = Simulation::new(0);
+        let handle = simulation.handle();
+        let result = simulation.spawn(async move {
+            let long = handle.sleep(Duration::from_secs(100));
+            let short = handle.sleep(Duration::from_secs(1));
+            futures::pin_mut!(long, short);
+            futures::future::select(long, short).await;
+        });
+        simulation.run();
+        block_on(result).unwrap();
+        // The clock never had to move to the cancelled deadline.
+        assert_eq!(simulation.now(), Duration::from_secs(1));
+        assert!(simulation.handle.state.clock.next_deadline().is_none());
+    }
+
+    #[test]
+    fn timer_futures_use_virtual_clock() {
+        let simulation = Simulation::new(0);
+        let handle = simulation.handle();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        for (name, secs) in [("minute", 60), ("second", 1)] {
+            let (handle, log) = (handle.clone(), log.clone());
+            simulation.spawn(async move {
+                TimerFuture::new(Duration::from_secs(secs)).await;
+                log.borrow_mut().push((name, handle.now()));
+            });
+        }
+        let start = Instant::now();
+        simulation.run();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            *log.borrow(),
+            [
+                ("second", Duration::from_secs(1)),
+                ("minute", Duration::from_secs(60)),
+            ]
+        );
+    }
+
+    /// Two tasks increment a counter non-atomically, with a yield between
+    /// the read and the write, so some interleavings lose an update.
+    fn lost_update(simulation: &Simulation) {
+        let counter = Rc::new(Cell::new(0));
+        for _ in 0..2 {
+            let counter = counter.clone();
+            simulation.spawn(async move {
+                let value = counter.get();
+                yield_now().await;
+                counter.set(value + 1);
+            });
+        }
+        simulation.run();
+        assert_eq!(counter.get(), 2, "lost an update");
+    }
+
+    #[test]
+    fn finds_and_replays_failing_seed() {
+        let payload = catch_unwind(|| check(0..100, lost_update)).unwrap_err();
+        let message = panic_message(&*payload).unwrap();
+        assert!(message.contains("lost an update"), "{message}");
+        let seed: u64 = message["simulation with seed ".len()..]
+            .split(' ')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        // Replaying the seed fails again, with the same schedule.
+        let schedules: Vec<_> = (0..2)
+            .map(|_| {
+                let simulation = Simulation::new(seed);
+                assert!(catch_unwind(AssertUnwindSafe(|| lost_update(&simulation))).is_err());
+                simulation.schedule()
+            })
+            .collect();
+        assert_eq!(schedules[0], schedules[1]);
+    }
+}
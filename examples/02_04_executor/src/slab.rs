@@ -0,0 +1,468 @@
+//! An executor which keeps its tasks in a fixed slab of reusable slots.
+//!
+//! `Spawner::spawn` allocates an `Arc<Task>` and boxes the future for every
+//! task. A `SlabExecutor<F>` instead allocates room for `capacity` futures
+//! of type `F` up front, and spawning a task only moves its future into a
+//! free slot. When every task is the same `async fn`, nothing is allocated
+//! per task at all; executors which mix futures can still use
+//! `BoxFuture<'static, ()>` as `F` and save the `Arc`.
+//!
+//! Wakers don't point at the task either. A task is identified by the index
+//! of its slot, the slot's generation, which changes every time the slot is
+//! reused, and the executor's index in a global registry. All three are
+//! packed into the waker's data pointer, so cloning and dropping a waker
+//! cost nothing. Waking looks the executor up, and does nothing if the task
+//! is gone: either the executor has been dropped, or the generation no
+//! longer matches.
+//!
+//! ```
+//! # use example_02_04_executor::slab::new_slab_executor_and_spawner;
+//! # use futures::future::{BoxFuture, FutureExt};
+//! let (executor, spawner) = new_slab_executor_and_spawner::<BoxFuture<'static, ()>>(16);
+//! spawner.spawn(async { println!("howdy!") }.boxed()).ok().unwrap();
+//! drop(spawner);
+//! executor.run();
+//! ```
+
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, Weak},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+use crate::coop;
+use crate::ready_queue::{Priority, ReadyQueue};
+
+/// Bits of a packed task id holding the slot index, generation and
+/// executor, from least to most significant.
+const INDEX_BITS: u32 = usize::BITS / 2;
+const GENERATION_BITS: u32 = usize::BITS / 4;
+const EXECUTOR_BITS: u32 = usize::BITS - INDEX_BITS - GENERATION_BITS;
+
+/// Executor which polls the futures stored in its slab. See the module docs.
+pub struct SlabExecutor<F> {
+    shared: Arc<Shared<F>>,
+}
+
+/// `SlabSpawner` moves new futures into free slots of a `SlabExecutor`.
+pub struct SlabSpawner<F> {
+    shared: Arc<Shared<F>>,
+}
+
+struct Shared<F> {
+    /// Index of this executor in `EXECUTORS`.
+    executor: usize,
+    slots: Box<[Slot<F>]>,
+    /// Indices of the empty slots.
+    free: Mutex<Vec<usize>>,
+    ready_queue: ReadyQueue<usize>,
+}
+
+struct Slot<F> {
+    /// Changes each time the slot is freed, so that wakers of the slot's
+    /// previous tasks are ignored.
+    generation: AtomicUsize,
+    /// Whether the slot's index is already in the ready queue.
+    scheduled: AtomicBool,
+    future: Mutex<Option<F>>,
+}
+
+/// Identifies a task across every slab executor. See the module docs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TaskId {
+    executor: usize,
+    generation: usize,
+    index: usize,
+}
+
+/// Lets a waker reach an executor without knowing its future type.
+trait WakeSlot: Send + Sync {
+    fn wake(&self, index: usize, generation: usize);
+}
+
+/// Every live slab executor, indexed by `TaskId::executor`. Entries are
+/// reused once an executor is dropped; an old waker which reaches the new
+/// executor at worst causes a spurious wake.
+static EXECUTORS: RwLock<Vec<Option<Weak<dyn WakeSlot>>>> = RwLock::new(Vec::new());
+
+/// Create an executor with room for `capacity` tasks, and a spawner for it.
+///
+/// # Panics
+///
+/// Panics if `capacity` doesn't fit in a task id, or if too many slab
+/// executors are alive at once.
+pub fn new_slab_executor_and_spawner<F>(capacity: usize) -> (SlabExecutor<F>, SlabSpawner<F>)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    assert!(capacity <= mask(INDEX_BITS) + 1, "slab capacity is too large");
+    let mut executors = EXECUTORS.write().unwrap();
+    let executor = match executors.iter().position(Option::is_none) {
+        Some(executor) => executor,
+        None => {
+            assert!(executors.len() <= mask(EXECUTOR_BITS), "too many slab executors");
+            executors.push(None);
+            executors.len() - 1
+        }
+    };
+    let shared = Arc::new(Shared {
+        executor,
+        slots: (0..capacity)
+            .map(|_| Slot {
+                generation: AtomicUsize::new(0),
+                scheduled: AtomicBool::new(false),
+                future: Mutex::new(None),
+            })
+            .collect(),
+        // Reversed, so that the first slots are used first.
+        free: Mutex::new((0..capacity).rev().collect()),
+        ready_queue: ReadyQueue::new(usize::MAX),
+    });
+    executors[executor] = Some(Arc::downgrade(&shared) as Weak<dyn WakeSlot>);
+    drop(executors);
+    shared.ready_queue.acquire_handle();
+    let spawner = SlabSpawner {
+        shared: shared.clone(),
+    };
+    (SlabExecutor { shared }, spawner)
+}
+
+impl<F: Future<Output = ()> + Send + 'static> SlabSpawner<F> {
+    /// Move `future` into a free slot and queue it. If every slot is taken,
+    /// the future is handed back instead.
+    pub fn spawn(&self, future: F) -> Result<(), F> {
+        let Some(index) = self.shared.free.lock().unwrap().pop() else {
+            return Err(future);
+        };
+        let slot = &self.shared.slots[index];
+        *slot.future.lock().unwrap() = Some(future);
+        slot.scheduled.store(true, Ordering::SeqCst);
+        self.shared.ready_queue.acquire_handle();
+        self.shared.ready_queue.push(index, Priority::Normal);
+        Ok(())
+    }
+}
+
+impl<F> Clone for SlabSpawner<F> {
+    fn clone(&self) -> Self {
+        self.shared.ready_queue.acquire_handle();
+        SlabSpawner {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<F> Drop for SlabSpawner<F> {
+    fn drop(&mut self) {
+        self.shared.ready_queue.release_handle();
+    }
+}
+
+impl<F: Future<Output = ()> + Send + 'static> SlabExecutor<F> {
+    /// Run tasks until every `SlabSpawner` and every task has been dropped.
+    /// A task which panics is dropped, and the panic then unwinds out of `run`.
+    ///
+    /// Wakers don't keep a task alive, so a task is only dropped once it
+    /// completes. Unlike `Executor::run`, which returns once a pending task's
+    /// last waker is dropped, `run` keeps waiting for a task which nothing
+    /// can wake any more, such as `future::pending()`, and never returns.
+    pub fn run(&self) {
+        while let Some(index) = self.shared.ready_queue.pop() {
+            self.poll_slot(index);
+        }
+    }
+
+    /// Run tasks until none of them is ready to make progress, without
+    /// waiting for wakeups from other threads.
+    pub fn run_until_stalled(&self) {
+        while let Some(index) = self.shared.ready_queue.try_pop() {
+            self.poll_slot(index);
+        }
+    }
+
+    fn poll_slot(&self, index: usize) {
+        let slot = &self.shared.slots[index];
+        slot.scheduled.store(false, Ordering::SeqCst);
+        let mut future = slot.future.lock().unwrap();
+        let Some(pending) = future.as_mut() else {
+            // Woken through a stale id which still matched the generation.
+            return;
+        };
+        let waker = waker(TaskId {
+            executor: self.shared.executor,
+            generation: slot.generation.load(Ordering::SeqCst),
+            index,
+        });
+        let context = &mut Context::from_waker(&waker);
+        // SAFETY: the slots are allocated once and never move, and a future
+        // is only ever dropped in place, by overwriting the `Option`.
+        let pending = unsafe { Pin::new_unchecked(pending) };
+        // The panic is caught so that the slot is emptied before the lock on
+        // it is released, rather than left poisoned for `Drop` to trip over.
+        let poll = panic::catch_unwind(AssertUnwindSafe(|| {
+            coop::with_budget(|| pending.poll(context))
+        }));
+        match poll {
+            Ok((Poll::Pending, exhausted)) => {
+                if exhausted {
+                    waker.wake_by_ref();
+                }
+            }
+            Ok((Poll::Ready(()), _)) => self.free_slot(index, future),
+            Err(payload) => {
+                self.free_slot(index, future);
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+
+    /// Drop the task in slot `index` and make the slot available again.
+    fn free_slot(&self, index: usize, mut future: MutexGuard<'_, Option<F>>) {
+        let slot = &self.shared.slots[index];
+        *future = None;
+        let generation = slot.generation.load(Ordering::SeqCst);
+        slot.generation.store((generation + 1) & mask(GENERATION_BITS), Ordering::SeqCst);
+        drop(future);
+        self.shared.free.lock().unwrap().push(index);
+        self.shared.ready_queue.release_handle();
+    }
+}
+
+impl<F> Drop for SlabExecutor<F> {
+    fn drop(&mut self) {
+        // Tasks may hold a `SlabSpawner`, which would keep the slab alive
+        // forever. They may have been pinned by a poll, so they are dropped
+        // in place rather than moved out of their slots.
+        // A slot is only poisoned if a task's destructor panicked; this may
+        // run while that panic unwinds, so it must not panic again.
+        for slot in self.shared.slots.iter() {
+            *slot.future.lock().unwrap_or_else(PoisonError::into_inner) = None;
+        }
+    }
+}
+
+impl<F> Drop for Shared<F> {
+    fn drop(&mut self) {
+        EXECUTORS.write().unwrap()[self.executor] = None;
+    }
+}
+
+impl<F: Send> WakeSlot for Shared<F> {
+    fn wake(&self, index: usize, generation: usize) {
+        let Some(slot) = self.slots.get(index) else {
+            return;
+        };
+        if slot.generation.load(Ordering::SeqCst) == generation
+            && !slot.scheduled.swap(true, Ordering::SeqCst)
+        {
+            self.ready_queue.push(index, Priority::Normal);
+        }
+    }
+}
+
+impl TaskId {
+    fn pack(self) -> usize {
+        (self.executor << (INDEX_BITS + GENERATION_BITS))
+            | (self.generation << INDEX_BITS)
+            | self.index
+    }
+
+    fn unpack(id: usize) -> Self {
+        TaskId {
+            executor: id >> (INDEX_BITS + GENERATION_BITS),
+            generation: (id >> INDEX_BITS) & mask(GENERATION_BITS),
+            index: id & mask(INDEX_BITS),
+        }
+    }
+}
+
+fn mask(bits: u32) -> usize {
+    (1 << bits) - 1
+}
+
+/// The waker of the task `id`. It doesn't own anything, so it doesn't need
+/// to be dropped.
+fn waker(id: TaskId) -> Waker {
+    let raw = RawWaker::new(ptr::without_provenance(id.pack()), &VTABLE);
+    // SAFETY: the vtable's functions only look at the data pointer's
+    // address, never at what it points to.
+    unsafe { Waker::from_raw(raw) }
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone_waker, wake, wake, drop_waker);
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &VTABLE)
+}
+
+unsafe fn wake(data: *const ()) {
+    let id = TaskId::unpack(data.addr());
+    let executor = EXECUTORS
+        .read()
+        .unwrap()
+        .get(id.executor)
+        .and_then(|executor| executor.as_ref()?.upgrade());
+    if let Some(executor) = executor {
+        executor.wake(id.index, id.generation);
+    }
+}
+
+unsafe fn drop_waker(_data: *const ()) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{pending, poll_fn, BoxFuture, FutureExt};
+    use std::{marker::PhantomPinned, task::Poll, time::Duration};
+    use timer_future::TimerFuture;
+
+    #[test]
+    fn task_ids_round_trip() {
+        let id = TaskId {
+            executor: mask(EXECUTOR_BITS),
+            generation: 5,
+            index: mask(INDEX_BITS) - 1,
+        };
+        assert_eq!(TaskId::unpack(id.pack()), id);
+    }
+
+    #[test]
+    fn timers_wake_tasks() {
+        let (executor, spawner) = new_slab_executor_and_spawner(100);
+        let finished = Arc::new(AtomicUsize::new(0));
+        for millis in 0..100 {
+            let finished = finished.clone();
+            let task = async move {
+                TimerFuture::new(Duration::from_millis(millis % 10)).await;
+                finished.fetch_add(1, Ordering::SeqCst);
+            };
+            spawner.spawn(task.boxed()).ok().unwrap();
+        }
+        drop(spawner);
+        executor.run();
+        assert_eq!(finished.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn full_slab_hands_future_back() {
+        let (executor, spawner) = new_slab_executor_and_spawner::<BoxFuture<'static, ()>>(1);
+        spawner.spawn(async {}.boxed()).ok().unwrap();
+        assert!(spawner.spawn(async {}.boxed()).is_err());
+        executor.run_until_stalled();
+        // The first task finished, freeing its slot.
+        assert!(spawner.spawn(async {}.boxed()).is_ok());
+    }
+
+    #[test]
+    fn stale_waker_is_ignored() {
+        let (executor, spawner) = new_slab_executor_and_spawner::<BoxFuture<'static, ()>>(1);
+        let old_waker = Arc::new(Mutex::new(None));
+        let task_waker = old_waker.clone();
+        let task = poll_fn(move |cx| {
+            *task_waker.lock().unwrap() = Some(cx.waker().clone());
+            Poll::Ready(())
+        });
+        spawner.spawn(task.boxed()).ok().unwrap();
+        executor.run_until_stalled();
+
+        // The next task reuses the slot, with a new generation.
+        let polls = Arc::new(AtomicUsize::new(0));
+        let task_polls = polls.clone();
+        let new_waker = Arc::new(Mutex::new(None));
+        let task_waker = new_waker.clone();
+        let task = poll_fn(move |cx| {
+            task_polls.fetch_add(1, Ordering::SeqCst);
+            *task_waker.lock().unwrap() = Some(cx.waker().clone());
+            Poll::<()>::Pending
+        });
+        spawner.spawn(task.boxed()).ok().unwrap();
+        executor.run_until_stalled();
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+
+        let old_waker = old_waker.lock().unwrap().take().unwrap();
+        let new_waker = new_waker.lock().unwrap().take().unwrap();
+        assert!(!old_waker.will_wake(&new_waker));
+        old_waker.wake();
+        executor.run_until_stalled();
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+        new_waker.wake();
+        executor.run_until_stalled();
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn waker_outlives_executor() {
+        let (executor, spawner) = new_slab_executor_and_spawner::<BoxFuture<'static, ()>>(1);
+        let saved = Arc::new(Mutex::new(None));
+        let task_saved = saved.clone();
+        let task = async move {
+            poll_fn(|cx| {
+                *task_saved.lock().unwrap() = Some(cx.waker().clone());
+                Poll::Ready(())
+            })
+            .await;
+            pending::<()>().await;
+        };
+        spawner.spawn(task.boxed()).ok().unwrap();
+        executor.run_until_stalled();
+        drop((executor, spawner));
+        // Does nothing, rather than reaching a dropped executor.
+        saved.lock().unwrap().take().unwrap().wake();
+    }
+
+    #[test]
+    fn panicking_task_unwinds_out_of_run() {
+        let (executor, spawner) = new_slab_executor_and_spawner::<BoxFuture<'static, ()>>(2);
+        spawner.spawn(pending().boxed()).ok().unwrap();
+        spawner.spawn(async { panic!("task failed") }.boxed()).ok().unwrap();
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| executor.run())).unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"task failed"));
+        // The panicked task's slot was freed, and dropping the executor
+        // doesn't panic again.
+        assert!(spawner.spawn(async {}.boxed()).is_ok());
+        drop((executor, spawner));
+    }
+
+    /// A future which must not move once polled, and records where it was
+    /// polled and where it was dropped.
+    struct Pinned {
+        addresses: Arc<Mutex<Vec<usize>>>,
+        _pinned: PhantomPinned,
+    }
+
+    impl Future for Pinned {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+            let address = &*self as *const Self as usize;
+            self.addresses.lock().unwrap().push(address);
+            Poll::Pending
+        }
+    }
+
+    impl Drop for Pinned {
+        fn drop(&mut self) {
+            let address = self as *const Self as usize;
+            self.addresses.lock().unwrap().push(address);
+        }
+    }
+
+    #[test]
+    fn pending_task_dropped_in_place() {
+        let (executor, spawner) = new_slab_executor_and_spawner(1);
+        let addresses = Arc::new(Mutex::new(Vec::new()));
+        let task = Pinned {
+            addresses: addresses.clone(),
+            _pinned: PhantomPinned,
+        };
+        spawner.spawn(task).ok().unwrap();
+        executor.run_until_stalled();
+        drop((executor, spawner));
+        let addresses = addresses.lock().unwrap();
+        assert_eq!(addresses.len(), 2);
+        assert_eq!(addresses[0], addresses[1]);
+    }
+}
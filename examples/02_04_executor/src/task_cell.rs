@@ -0,0 +1,311 @@
+//! Lock-free storage for a task's future.
+//!
+//! The chapter's executor keeps each future in a `Mutex<Option<_>>` only to
+//! convince the compiler that it is never polled from two threads at once.
+//! `TaskCell` provides the same guarantee with an atomic state machine, and
+//! also tracks whether the task is queued, so that waking a task several
+//! times queues it only once:
+//!
+//! ```text
+//!            wake                 popped                 poll: Pending
+//!   IDLE ------------> SCHEDULED --------> RUNNING ---------------------> IDLE
+//!                          ^                  |  \
+//!                          |            wake  |   \ poll: Ready
+//!                          |                  v    \
+//!                          +------------- NOTIFIED  +-------------> COMPLETE
+//!                            poll: Pending
+//! ```
+//!
+//! Only the thread which moved the state from `SCHEDULED` to `RUNNING` may
+//! touch the future, which makes the `UnsafeCell` access sound.
+
+#[cfg(loom)]
+use loom::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+#[cfg(not(loom))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The states are bit sets, so that a wake is a single `fetch_or`.
+
+/// Not queued, not running. Only a wake can move the task on.
+const IDLE: usize = 0;
+/// In the ready queue.
+const SCHEDULED: usize = 0b001;
+/// Being polled.
+const RUNNING: usize = 0b010;
+/// Being polled, and woken since the poll started. The task is queued again
+/// once the poll returns.
+const NOTIFIED: usize = RUNNING | SCHEDULED;
+/// The future completed or was cancelled, and has been dropped. Wakes may
+/// still set the `SCHEDULED` bit, which is ignored from then on.
+const COMPLETE: usize = 0b100;
+
+pub(crate) struct TaskCell<F> {
+    state: AtomicUsize,
+    future: UnsafeCell<Option<F>>,
+}
+
+// SAFETY: the state machine ensures that only one thread at a time accesses
+// `future`, so sharing a `TaskCell` only requires the future to be `Send`.
+unsafe impl<F: Send> Send for TaskCell<F> {}
+unsafe impl<F: Send> Sync for TaskCell<F> {}
+
+/// What the executor has to do after `TaskCell::run`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RunOutcome {
+    /// The task is waiting for a wake.
+    Idle,
+    /// The task was woken while it was running. The caller must put it back
+    /// onto the ready queue.
+    Rescheduled,
+    /// The future completed and has been dropped.
+    Complete,
+    /// The task had already completed or been cancelled.
+    Skipped,
+}
+
+/// A coarse view of a task's state, for diagnostics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Phase {
+    Idle,
+    Scheduled,
+    Running,
+    Complete,
+}
+
+impl<F> TaskCell<F> {
+    /// Create a cell for a newly spawned task, which the caller is about to
+    /// place on the ready queue.
+    pub(crate) fn new(future: F) -> Self {
+        TaskCell {
+            state: AtomicUsize::new(SCHEDULED),
+            future: UnsafeCell::new(Some(future)),
+        }
+    }
+
+    /// Mark the task as woken. Returns `true` if the caller must place it on
+    /// the ready queue, which happens at most once until it is run again.
+    pub(crate) fn wake(&self) -> bool {
+        // Even a task which is already queued gets written to, so that the
+        // next poll synchronizes with the waker and sees whatever it did
+        // before waking the task.
+        self.state.fetch_or(SCHEDULED, Ordering::AcqRel) == IDLE
+    }
+
+    /// Run a task which was taken off the ready queue. `poll` gets exclusive
+    /// access to the future and returns whether it completed.
+    pub(crate) fn run(&self, poll: impl FnOnce(&mut F) -> bool) -> RunOutcome {
+        if self
+            .state
+            .compare_exchange(SCHEDULED, RUNNING, Ordering::Acquire, Ordering::Acquire)
+            .is_err()
+        {
+            return RunOutcome::Skipped;
+        }
+        // SAFETY: we moved the state to `RUNNING`, so no other thread will
+        // touch the future until we move it on.
+        let complete = self.future.with_mut(|future| {
+            let future = unsafe { (*future).as_mut() }.expect("running task has no future");
+            poll(future)
+        });
+        if complete {
+            // SAFETY: the state is still `RUNNING` or `NOTIFIED`.
+            self.future.with_mut(|future| unsafe { *future = None });
+            self.state.store(COMPLETE, Ordering::Release);
+            return RunOutcome::Complete;
+        }
+        match self
+            .state
+            .compare_exchange(RUNNING, IDLE, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => RunOutcome::Idle,
+            Err(NOTIFIED) => {
+                // Nothing else changes the state of a notified task, so the
+                // wake which notified it is now handed over to the caller.
+                self.state.store(SCHEDULED, Ordering::Release);
+                RunOutcome::Rescheduled
+            }
+            Err(state) => unreachable!("task in state {state:#b} while running"),
+        }
+    }
+
+    /// Get the task's current state. It may have changed by the time the
+    /// caller looks at it.
+    pub(crate) fn phase(&self) -> Phase {
+        match self.state.load(Ordering::Acquire) {
+            IDLE => Phase::Idle,
+            SCHEDULED => Phase::Scheduled,
+            RUNNING | NOTIFIED => Phase::Running,
+            _ => Phase::Complete,
+        }
+    }
+
+    /// Drop the future of a task which is not running. Returns `false` if the
+    /// task is running or has already completed.
+    pub(crate) fn cancel(&self) -> bool {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            if state != IDLE && state != SCHEDULED {
+                return false;
+            }
+            match self
+                .state
+                .compare_exchange_weak(state, RUNNING, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(actual) => state = actual,
+            }
+        }
+        // SAFETY: we moved the state to `RUNNING`, as in `run`.
+        self.future.with_mut(|future| unsafe { *future = None });
+        self.state.store(COMPLETE, Ordering::Release);
+        true
+    }
+}
+
+/// `std`'s `UnsafeCell`, with the closure-based API of loom's `UnsafeCell`.
+#[cfg(not(loom))]
+struct UnsafeCell<T>(std::cell::UnsafeCell<T>);
+
+#[cfg(not(loom))]
+impl<T> UnsafeCell<T> {
+    fn new(value: T) -> Self {
+        UnsafeCell(std::cell::UnsafeCell::new(value))
+    }
+
+    fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+        f(self.0.get())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wake_while_idle_schedules_once() {
+        let cell = TaskCell::new(());
+        assert_eq!(cell.run(|_| false), RunOutcome::Idle);
+        assert!(cell.wake());
+        assert!(!cell.wake());
+        assert_eq!(cell.run(|_| false), RunOutcome::Idle);
+    }
+
+    #[test]
+    fn wake_while_running_reschedules() {
+        let cell = TaskCell::new(());
+        let outcome = cell.run(|_| {
+            assert!(!cell.wake());
+            assert!(!cell.wake());
+            false
+        });
+        assert_eq!(outcome, RunOutcome::Rescheduled);
+        assert_eq!(cell.run(|_| true), RunOutcome::Complete);
+        assert!(!cell.wake());
+        assert_eq!(cell.run(|_| unreachable!()), RunOutcome::Skipped);
+    }
+
+    #[test]
+    fn cancel_scheduled_task() {
+        let cell = TaskCell::new(());
+        assert!(cell.cancel());
+        assert!(!cell.cancel());
+        assert_eq!(cell.run(|_| unreachable!()), RunOutcome::Skipped);
+    }
+}
+
+/// Model tests which explore every interleaving of the state machine. Run
+/// them with `RUSTFLAGS="--cfg loom" cargo test --release --lib task_cell`.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::{
+        sync::{atomic::AtomicBool, Arc},
+        thread,
+    };
+
+    #[test]
+    fn concurrent_wakes_schedule_once() {
+        loom::model(|| {
+            let cell = Arc::new(TaskCell::new(0));
+            assert_eq!(cell.run(|_| false), RunOutcome::Idle);
+            let other = cell.clone();
+            let thread = thread::spawn(move || other.wake());
+            let here = cell.wake();
+            let there = thread.join().unwrap();
+            assert!(here ^ there, "task must be queued exactly once");
+        });
+    }
+
+    #[test]
+    fn wake_during_poll_is_not_lost() {
+        loom::model(|| {
+            let cell = Arc::new(TaskCell::new(0));
+            let event = Arc::new(AtomicBool::new(false));
+            let (other, other_event) = (cell.clone(), event.clone());
+            let thread = thread::spawn(move || {
+                other_event.store(true, Ordering::Release);
+                other.wake()
+            });
+            let mut seen = false;
+            let outcome = cell.run(|_| {
+                seen = event.load(Ordering::Acquire);
+                false
+            });
+            let queued_by_wake = thread.join().unwrap();
+            let rescheduled = outcome == RunOutcome::Rescheduled;
+            // A poll which missed the event must be followed by another one,
+            // and the task must not be queued twice.
+            assert!(seen || rescheduled || queued_by_wake);
+            assert!(!(rescheduled && queued_by_wake));
+        });
+    }
+
+    #[test]
+    fn polls_never_overlap() {
+        loom::model(|| {
+            let cell = Arc::new(TaskCell::new(0));
+            let other = cell.clone();
+            // A second "executor thread" wakes the task and runs it as soon
+            // as it is handed the task. Loom reports an error if the two
+            // runs ever access the future at the same time.
+            let thread = thread::spawn(move || {
+                if other.wake() {
+                    other.run(|counter| {
+                        *counter += 1;
+                        false
+                    });
+                }
+            });
+            if cell.run(|counter| {
+                *counter += 1;
+                false
+            }) == RunOutcome::Rescheduled
+            {
+                cell.run(|counter| {
+                    *counter += 1;
+                    false
+                });
+            }
+            thread.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn cancel_races_with_wake() {
+        loom::model(|| {
+            let cell = Arc::new(TaskCell::new(0));
+            assert_eq!(cell.run(|_| false), RunOutcome::Idle);
+            let other = cell.clone();
+            let thread = thread::spawn(move || {
+                if other.wake() {
+                    other.run(|_| false);
+                }
+            });
+            cell.cancel();
+            thread.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,432 @@
+//! A multi-threaded version of the chapter's executor.
+//!
+//! Every worker thread owns a local ready queue. Tasks spawned from outside
+//! the executor go onto a shared global injector queue, while tasks woken
+//! from a worker thread are pushed onto that worker's local queue. A worker
+//! that runs out of work first checks the injector and then steals half of
+//! the tasks queued on another worker.
+//!
+//! As in `Executor`, a `TaskCell` makes sure that a task is only ever queued
+//! once, however often it is woken, and a task which panics is dropped and
+//! its `JoinHandle` resolved to `JoinError::Panicked`, without taking its
+//! worker down with it.
+
+use futures::future::{BoxFuture, FutureExt};
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    future::Future,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::{Arc, Condvar, Mutex, Weak},
+    task::Context,
+    thread,
+};
+
+use crate::join_handle::{with_join_handle, JoinHandle, PanicReporter, Schedule};
+use crate::raw_waker::{waker_ref, Wake};
+use crate::task_cell::{RunOutcome, TaskCell};
+
+/// Multi-threaded task executor with per-worker queues and work stealing.
+pub struct Executor {
+    shared: Arc<Shared>,
+}
+
+/// `Spawner` spawns new futures onto the executor.
+pub struct Spawner {
+    shared: Arc<Shared>,
+}
+
+/// A future that can reschedule itself to be polled by an `Executor`.
+struct Task {
+    /// In-progress future that should be pushed to completion.
+    ///
+    /// A task woken while it is being polled is only queued again once the
+    /// poll returns, so two workers never poll it at the same time.
+    future: TaskCell<BoxFuture<'static, ()>>,
+
+    /// Passes the payload of a panic in `future` on to its `JoinHandle`.
+    panic_reporter: PanicReporter,
+
+    /// Handle to the executor, used to place the task back onto a queue.
+    shared: Arc<Shared>,
+}
+
+/// State shared between the executor, its spawners and its tasks.
+struct Shared {
+    /// Queue for tasks spawned or woken from outside a worker thread.
+    injector: Mutex<VecDeque<Arc<Task>>>,
+
+    /// One local queue per worker. The owning worker pops from the front,
+    /// other workers steal from the back.
+    locals: Vec<Mutex<VecDeque<Arc<Task>>>>,
+
+    /// Number of tasks sitting in any of the queues.
+    queued: AtomicUsize,
+
+    /// Number of live `Spawner`s and `Task`s. Once this drops to zero no new
+    /// work can ever arrive and the workers shut down, just like the
+    /// chapter's executor stops once every `SyncSender` has been dropped.
+    handles: AtomicUsize,
+
+    /// Used by idle workers to sleep until more work is queued.
+    sleep_lock: Mutex<()>,
+    sleep_condvar: Condvar,
+}
+
+thread_local! {
+    /// The executor and worker index of the current thread, if it is a
+    /// worker thread.
+    static WORKER: Cell<Option<(*const Shared, usize)>> = const { Cell::new(None) };
+}
+
+/// Create an executor with `num_workers` worker threads, along with a
+/// spawner for it.
+pub fn new_executor_and_spawner(num_workers: usize) -> (Executor, Spawner) {
+    assert!(num_workers > 0, "an executor needs at least one worker");
+    let shared = Arc::new(Shared {
+        injector: Mutex::new(VecDeque::new()),
+        locals: (0..num_workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+        queued: AtomicUsize::new(0),
+        handles: AtomicUsize::new(1),
+        sleep_lock: Mutex::new(()),
+        sleep_condvar: Condvar::new(),
+    });
+    let spawner = Spawner {
+        shared: shared.clone(),
+    };
+    (Executor { shared }, spawner)
+}
+
+impl Spawner {
+    /// Spawn `future` onto the executor, returning a `JoinHandle` that
+    /// resolves to its output.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (future, handle) = with_join_handle(future);
+        self.shared.handles.fetch_add(1, Ordering::SeqCst);
+        let task = Arc::new(Task {
+            future: TaskCell::new(future.boxed()),
+            panic_reporter: handle.panic_reporter(),
+            shared: self.shared.clone(),
+        });
+        handle.bind(Arc::downgrade(&task) as Weak<dyn Schedule>);
+        self.shared.schedule(task);
+        handle
+    }
+}
+
+impl Clone for Spawner {
+    fn clone(&self) -> Self {
+        self.shared.handles.fetch_add(1, Ordering::SeqCst);
+        Spawner {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Spawner {
+    fn drop(&mut self) {
+        self.shared.release_handle();
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.shared.release_handle();
+    }
+}
+
+impl Wake for Task {
+    fn wake_by_ref(self: &Arc<Self>) {
+        if self.future.wake() {
+            self.shared.schedule(self.clone());
+        }
+    }
+}
+
+impl Schedule for Task {
+    fn schedule(self: Arc<Self>) {
+        Wake::wake(self)
+    }
+}
+
+impl Shared {
+    /// Push `task` onto the local queue of the current worker, or onto the
+    /// injector when called from outside this executor.
+    fn schedule(&self, task: Arc<Task>) {
+        match self.current_worker() {
+            Some(index) => self.locals[index].lock().unwrap().push_back(task),
+            None => self.injector.lock().unwrap().push_back(task),
+        }
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        // Taking the lock ensures that a worker which just found every queue
+        // empty is either already waiting (and gets notified) or has not yet
+        // checked `queued` (and will see the new task).
+        let _guard = self.sleep_lock.lock().unwrap();
+        self.sleep_condvar.notify_one();
+    }
+
+    fn release_handle(&self) {
+        if self.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _guard = self.sleep_lock.lock().unwrap();
+            self.sleep_condvar.notify_all();
+        }
+    }
+
+    /// Index of the current thread if it is one of this executor's workers.
+    fn current_worker(&self) -> Option<usize> {
+        WORKER.with(|worker| match worker.get() {
+            Some((shared, index)) if std::ptr::eq(shared, self) => Some(index),
+            _ => None,
+        })
+    }
+
+    /// Find the next task for worker `index`: its own queue first, then the
+    /// injector, then the other workers' queues.
+    fn find_task(&self, index: usize) -> Option<Arc<Task>> {
+        // Each queue is locked in its own statement so that no guard is
+        // still held when `steal` locks this worker's queue again.
+        let mut task = self.locals[index].lock().unwrap().pop_front();
+        if task.is_none() {
+            task = self.injector.lock().unwrap().pop_front();
+        }
+        if task.is_none() {
+            task = self.steal(index);
+        }
+        if task.is_some() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+        task
+    }
+
+    /// Steal half of the tasks queued on another worker, keeping one to run
+    /// and moving the rest onto worker `index`'s local queue.
+    fn steal(&self, index: usize) -> Option<Arc<Task>> {
+        let num_workers = self.locals.len();
+        for offset in 1..num_workers {
+            let victim = (index + offset) % num_workers;
+            let mut stolen = {
+                let mut victim_queue = self.locals[victim].lock().unwrap();
+                let len = victim_queue.len();
+                victim_queue.split_off(len / 2)
+            };
+            if let Some(task) = stolen.pop_front() {
+                self.locals[index].lock().unwrap().append(&mut stolen);
+                return Some(task);
+            }
+        }
+        None
+    }
+}
+
+impl Executor {
+    /// Run the executor on its worker threads until every `Spawner` has been
+    /// dropped and every task has either completed or been dropped.
+    pub fn run(&self) {
+        thread::scope(|scope| {
+            for index in 0..self.shared.locals.len() {
+                let shared = &*self.shared;
+                scope.spawn(move || shared.work(index));
+            }
+        });
+    }
+}
+
+impl Shared {
+    fn work(&self, index: usize) {
+        WORKER.with(|worker| worker.set(Some((self as *const Shared, index))));
+        loop {
+            if let Some(task) = self.find_task(index) {
+                self.poll_task(task);
+                continue;
+            }
+
+            let guard = self.sleep_lock.lock().unwrap();
+            if self.queued.load(Ordering::SeqCst) > 0 {
+                continue;
+            }
+            if self.handles.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            drop(self.sleep_condvar.wait(guard).unwrap());
+        }
+        WORKER.with(|worker| worker.set(None));
+    }
+
+    fn poll_task(&self, task: Arc<Task>) {
+        let waker = waker_ref(&task);
+        let context = &mut Context::from_waker(&waker);
+        let outcome = task.future.run(|future| {
+            // Catching the panic here keeps the worker thread alive, and
+            // leaves the task in a state where its future can be dropped. The
+            // panic hook has already reported it.
+            match catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(context))) {
+                Ok(poll) => poll.is_ready(),
+                Err(payload) => {
+                    task.panic_reporter.report(payload);
+                    true
+                }
+            }
+        });
+        if outcome == RunOutcome::Rescheduled {
+            self.schedule(task);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block_on, join_handle::panic_message, JoinError};
+    use futures::future::poll_fn;
+    use std::{
+        collections::HashSet,
+        pin::Pin,
+        sync::Barrier,
+        task::{Poll, Waker},
+        time::Duration,
+    };
+    use timer_future::TimerFuture;
+
+    /// Returns `Pending` once, waking itself immediately, so that the task
+    /// is requeued from the worker thread that polled it.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn tasks_run_on_every_worker() {
+        const WORKERS: usize = 4;
+        let (executor, spawner) = new_executor_and_spawner(WORKERS);
+        // Each task blocks until all of them are running, which can only
+        // happen if they are polled on `WORKERS` threads at once.
+        let barrier = Arc::new(Barrier::new(WORKERS));
+        let thread_ids = Arc::new(Mutex::new(HashSet::new()));
+        for _ in 0..WORKERS {
+            let barrier = barrier.clone();
+            let thread_ids = thread_ids.clone();
+            spawner.spawn(async move {
+                thread_ids.lock().unwrap().insert(thread::current().id());
+                barrier.wait();
+            });
+        }
+        drop(spawner);
+        executor.run();
+        assert_eq!(thread_ids.lock().unwrap().len(), WORKERS);
+    }
+
+    #[test]
+    fn every_spawned_future_finishes() {
+        let (executor, spawner) = new_executor_and_spawner(4);
+        let completed = Arc::new(AtomicUsize::new(0));
+        for _ in 0..100 {
+            let inner_spawner = spawner.clone();
+            let completed = completed.clone();
+            spawner.spawn(async move {
+                // Tasks spawned from a worker thread and tasks woken from a
+                // worker thread both go through the local queues.
+                for _ in 0..10 {
+                    let completed = completed.clone();
+                    inner_spawner.spawn(async move {
+                        YieldNow(false).await;
+                        completed.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+                YieldNow(false).await;
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(spawner);
+        executor.run();
+        assert_eq!(completed.load(Ordering::SeqCst), 100 * 11);
+    }
+
+    #[test]
+    fn wakes_from_other_threads() {
+        let (executor, spawner) = new_executor_and_spawner(2);
+        let completed = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let completed = completed.clone();
+            spawner.spawn(async move {
+                TimerFuture::new(Duration::from_millis(10)).await;
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(spawner);
+        executor.run();
+        assert_eq!(completed.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn join_handles_across_workers() {
+        let (executor, spawner) = new_executor_and_spawner(4);
+        let handles: Vec<_> = (0..100u64)
+            .map(|i| spawner.spawn(async move { i * 2 }))
+            .collect();
+        let sum = spawner.spawn(async move {
+            let mut sum = 0;
+            for handle in handles {
+                sum += handle.await.unwrap();
+            }
+            sum
+        });
+        drop(spawner);
+        executor.run();
+        assert_eq!(block_on(sum).unwrap(), 9900);
+    }
+
+    #[test]
+    fn panicking_task_keeps_worker_running() {
+        let (executor, spawner) = new_executor_and_spawner(1);
+        let panicked = spawner.spawn(async { panic!("task failed") });
+        let after = spawner.spawn(async { 42 });
+        drop(spawner);
+        executor.run();
+        match block_on(panicked) {
+            Err(JoinError::Panicked(payload)) => {
+                assert_eq!(panic_message(&*payload), Some("task failed"));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert_eq!(block_on(after).unwrap(), 42);
+    }
+
+    #[test]
+    fn repeated_wakes_queue_task_once() {
+        let (executor, spawner) = new_executor_and_spawner(1);
+        let polls = Arc::new(AtomicUsize::new(0));
+        let saved: Arc<Mutex<Option<Waker>>> = Arc::new(Mutex::new(None));
+        let (task_polls, task_saved) = (polls.clone(), saved.clone());
+        spawner.spawn(poll_fn(move |cx| {
+            if task_polls.fetch_add(1, Ordering::SeqCst) == 0 {
+                *task_saved.lock().unwrap() = Some(cx.waker().clone());
+            }
+            Poll::<()>::Pending
+        }));
+        // Runs after the first task's first poll, on the only worker, so the
+        // first task stays queued through all of the wakes.
+        spawner.spawn(async move {
+            let waker = saved.lock().unwrap().take().unwrap();
+            for _ in 0..10 {
+                waker.wake_by_ref();
+            }
+        });
+        drop(spawner);
+        executor.run();
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+    }
+}
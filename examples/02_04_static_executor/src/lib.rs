@@ -0,0 +1,70 @@
+//! A variant of the executor from the "Applied: Build an Executor" chapter
+//! which needs neither the standard library nor a heap allocator, as on a
+//! microcontroller.
+//!
+//! Without an allocator, tasks can't be boxed. Instead, they live in a
+//! `TaskPool` declared as a `static` with `task_pool!`, which has a fixed
+//! number of slots, each big enough for a future of up to a fixed size. A
+//! waker is a pointer to its task's slot with a `static` vtable, and waking
+//! a task sets its bit in the pool's ready bitset, which the `Executor`
+//! scans for tasks to poll.
+//!
+//! ```
+//! use example_02_04_static_executor::{task_pool, Executor, MockTicks};
+//!
+//! task_pool!(static POOL: tasks = 4, size = 64);
+//! static TICKS: MockTicks<4> = MockTicks::new();
+//!
+//! POOL.spawn(async {
+//!     TICKS.delay(10).await;
+//! }).ok().unwrap();
+//! let executor = Executor::new(&POOL);
+//! loop {
+//!     executor.run_until_idle();
+//!     if POOL.live_tasks() == 0 {
+//!         break;
+//!     }
+//!     // On hardware, the executor would sleep until the next interrupt,
+//!     // and a timer driver would advance its ticks.
+//!     TICKS.advance(1);
+//! }
+//! assert_eq!(TICKS.now(), 10);
+//! ```
+//!
+//! Spawning a future which doesn't fit in the pool's slots fails to compile:
+//!
+//! ```compile_fail
+//! # use example_02_04_static_executor::task_pool;
+//! task_pool!(static POOL: tasks = 1, size = 8);
+//! let big = [0u8; 64];
+//! POOL.spawn(async move {
+//!     core::future::ready(()).await;
+//!     drop(big);
+//! });
+//! ```
+
+#![cfg_attr(not(test), no_std)]
+
+mod pool;
+mod ticks;
+
+pub use pool::{Executor, TaskPool};
+pub use ticks::{Delay, MockTicks};
+
+/// Declare a `static` `TaskPool` with room for `tasks` futures of up to
+/// `size` bytes each:
+///
+/// ```
+/// # use example_02_04_static_executor::task_pool;
+/// task_pool!(static POOL: tasks = 8, size = 256);
+/// ```
+#[macro_export]
+macro_rules! task_pool {
+    ($vis:vis static $name:ident: tasks = $tasks:expr, size = $size:expr) => {
+        $vis static $name: $crate::TaskPool<
+            { $tasks },
+            { $size },
+            { usize::div_ceil($tasks, 32) },
+        > = $crate::TaskPool::new();
+    };
+}
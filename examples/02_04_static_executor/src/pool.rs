@@ -0,0 +1,370 @@
+use core::{
+    cell::{Cell, UnsafeCell},
+    future::Future,
+    marker::PhantomData,
+    mem::{align_of, size_of, MaybeUninit},
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU8, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+/// Alignment of every slot's storage, and so the largest alignment a
+/// spawned future may have.
+const ALIGN: usize = 16;
+
+/// Number of tasks per word of the ready bitset.
+const BITS: usize = u32::BITS as usize;
+
+/// States of a slot.
+const FREE: u8 = 0;
+/// A future is being moved into the slot, so it mustn't be polled yet.
+const SPAWNING: u8 = 1;
+const LIVE: u8 = 2;
+
+/// A fixed number of task slots, each of which can hold a future of up to
+/// `SIZE` bytes, and the bitset of tasks which are ready to be polled.
+/// Declare one with `task_pool!`, which works out `WORDS`, the number of
+/// words in the bitset.
+pub struct TaskPool<const N: usize, const SIZE: usize, const WORDS: usize> {
+    slots: [Slot<SIZE>; N],
+    ready: [AtomicU32; WORDS],
+    /// Set once an `Executor` has been created for the pool.
+    claimed: AtomicBool,
+}
+
+/// Polls the tasks of a `TaskPool`. There is at most one per pool, and it
+/// can't be shared between threads, so a task is never polled twice at once.
+pub struct Executor<const N: usize, const SIZE: usize, const WORDS: usize> {
+    pool: &'static TaskPool<N, SIZE, WORDS>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+struct Slot<const SIZE: usize> {
+    /// What the slot's wakers point to.
+    header: Header,
+    state: AtomicU8,
+    /// The type-erased functions of the future in `storage`, set while the
+    /// slot isn't `FREE`.
+    vtable: UnsafeCell<Option<TaskVTable>>,
+    storage: UnsafeCell<Storage<SIZE>>,
+}
+
+/// The part of a slot which doesn't depend on its size.
+struct Header {
+    index: usize,
+    /// The word of the ready bitset which holds this slot's bit. A pool's
+    /// address isn't known when it is created, so this is set on spawn.
+    ready: AtomicPtr<AtomicU32>,
+}
+
+#[repr(C, align(16))]
+struct Storage<const SIZE: usize>([MaybeUninit<u8>; SIZE]);
+
+#[derive(Clone, Copy)]
+struct TaskVTable {
+    poll: unsafe fn(*mut (), &mut Context<'_>) -> Poll<()>,
+    drop: unsafe fn(*mut ()),
+}
+
+// SAFETY: futures must be `Send` to be spawned, and the slot states ensure
+// that only one thread accesses a slot's future at a time.
+unsafe impl<const N: usize, const SIZE: usize, const WORDS: usize> Sync for TaskPool<N, SIZE, WORDS> {}
+
+impl<const N: usize, const SIZE: usize, const WORDS: usize> TaskPool<N, SIZE, WORDS> {
+    /// Create an empty pool. Use `task_pool!` instead, which picks `WORDS`.
+    pub const fn new() -> Self {
+        assert!(WORDS * BITS >= N, "the ready bitset is too small");
+        let mut slots = [const { Slot::new() }; N];
+        let mut index = 0;
+        while index < N {
+            slots[index].header.index = index;
+            index += 1;
+        }
+        TaskPool {
+            slots,
+            ready: [const { AtomicU32::new(0) }; WORDS],
+            claimed: AtomicBool::new(false),
+        }
+    }
+
+    /// Move `future` into a free slot and mark it as ready. If every slot is
+    /// taken, the future is handed back instead.
+    ///
+    /// Futures bigger than the pool's slots fail to compile.
+    pub fn spawn<F>(&'static self, future: F) -> Result<(), F>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        const {
+            assert!(size_of::<F>() <= SIZE, "the future doesn't fit in the pool's slots");
+            assert!(align_of::<F>() <= ALIGN, "the future's alignment is too large");
+        }
+        let free = self.slots.iter().find(|slot| {
+            slot.state
+                .compare_exchange(FREE, SPAWNING, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        });
+        let Some(slot) = free else {
+            return Err(future);
+        };
+        // SAFETY: the slot is `SPAWNING`, so nothing else touches it, and
+        // its storage is big enough and aligned for `F`.
+        unsafe {
+            slot.storage.get().cast::<F>().write(future);
+            *slot.vtable.get() = Some(TaskVTable {
+                poll: poll_future::<F>,
+                drop: drop_future::<F>,
+            });
+        }
+        let word = &self.ready[slot.header.index / BITS];
+        slot.header.ready.store(ptr::from_ref(word).cast_mut(), Ordering::Release);
+        slot.state.store(LIVE, Ordering::Release);
+        slot.header.wake();
+        Ok(())
+    }
+
+    /// Number of slots holding a task.
+    pub fn live_tasks(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.state.load(Ordering::Acquire) != FREE)
+            .count()
+    }
+
+    /// Poll the task in `slot` if there is one, and free the slot once the
+    /// task completes.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called by the pool's `Executor`.
+    unsafe fn poll_slot(&'static self, slot: &'static Slot<SIZE>) {
+        // A slot can be woken after its task completed, through a stale
+        // waker, or before its future has been moved in.
+        if slot.state.load(Ordering::Acquire) != LIVE {
+            return;
+        }
+        let vtable = (*slot.vtable.get()).expect("live slot without a future");
+        let waker = slot.header.waker();
+        let context = &mut Context::from_waker(&waker);
+        let future = slot.storage.get().cast::<()>();
+        if (vtable.poll)(future, context).is_ready() {
+            (vtable.drop)(future);
+            *slot.vtable.get() = None;
+            slot.state.store(FREE, Ordering::Release);
+        }
+    }
+}
+
+impl<const N: usize, const SIZE: usize, const WORDS: usize> Default for TaskPool<N, SIZE, WORDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const SIZE: usize, const WORDS: usize> Executor<N, SIZE, WORDS> {
+    /// Create the executor for `pool`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool already has an executor.
+    pub fn new(pool: &'static TaskPool<N, SIZE, WORDS>) -> Self {
+        assert!(
+            !pool.claimed.swap(true, Ordering::AcqRel),
+            "the pool already has an executor"
+        );
+        Executor {
+            pool,
+            _not_sync: PhantomData,
+        }
+    }
+
+    /// Poll the ready tasks until none is left. Tasks woken while this runs,
+    /// including by each other, are polled before it returns.
+    ///
+    /// Once it returns, the caller can wait for an interrupt which may wake
+    /// a task, and then call it again.
+    pub fn run_until_idle(&self) {
+        loop {
+            let mut polled = false;
+            for (word_index, word) in self.pool.ready.iter().enumerate() {
+                let mut bits = word.swap(0, Ordering::Acquire);
+                while bits != 0 {
+                    let index = word_index * BITS + bits.trailing_zeros() as usize;
+                    bits &= bits - 1;
+                    // SAFETY: `self` is the pool's only executor, and isn't
+                    // `Sync`, so this is the only thread polling its tasks.
+                    unsafe { self.pool.poll_slot(&self.pool.slots[index]) };
+                    polled = true;
+                }
+            }
+            if !polled {
+                return;
+            }
+        }
+    }
+}
+
+impl<const SIZE: usize> Slot<SIZE> {
+    const fn new() -> Self {
+        Slot {
+            header: Header {
+                index: 0,
+                ready: AtomicPtr::new(ptr::null_mut()),
+            },
+            state: AtomicU8::new(FREE),
+            vtable: UnsafeCell::new(None),
+            storage: UnsafeCell::new(Storage([MaybeUninit::uninit(); SIZE])),
+        }
+    }
+}
+
+impl Header {
+    fn wake(&self) {
+        let word = self.ready.load(Ordering::Acquire);
+        // SAFETY: wakers only exist for slots which have been spawned into,
+        // which set `ready` to a word of the pool's `static` bitset.
+        let word = unsafe { &*word };
+        word.fetch_or(1 << (self.index % BITS), Ordering::Release);
+    }
+
+    fn waker(&'static self) -> Waker {
+        let raw = RawWaker::new(ptr::from_ref(self).cast(), &VTABLE);
+        // SAFETY: the data is a `&'static Header`, as `VTABLE` expects.
+        unsafe { Waker::from_raw(raw) }
+    }
+}
+
+/// The vtable of every task's waker. Wakers point into a `static` pool, so
+/// cloning and dropping them does nothing.
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone_waker, wake, wake, drop_waker);
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &VTABLE)
+}
+
+unsafe fn wake(data: *const ()) {
+    (*data.cast::<Header>()).wake();
+}
+
+unsafe fn drop_waker(_data: *const ()) {}
+
+unsafe fn poll_future<F: Future<Output = ()>>(future: *mut (), cx: &mut Context<'_>) -> Poll<()> {
+    // The slot lives in a `static` and the future is dropped in place, so
+    // it never moves once polled.
+    Pin::new_unchecked(&mut *future.cast::<F>()).poll(cx)
+}
+
+unsafe fn drop_future<F>(future: *mut ()) {
+    ptr::drop_in_place(future.cast::<F>());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task_pool;
+    use std::{
+        sync::atomic::AtomicUsize,
+        sync::{Arc, Mutex},
+        vec::Vec,
+    };
+
+    #[test]
+    fn tasks_run_to_completion() {
+        task_pool!(static POOL: tasks = 4, size = 64);
+        let executor = Executor::new(&POOL);
+        let finished = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let finished = finished.clone();
+            POOL.spawn(async move {
+                finished.fetch_add(1, Ordering::SeqCst);
+            })
+            .ok()
+            .unwrap();
+        }
+        assert_eq!(POOL.live_tasks(), 4);
+        executor.run_until_idle();
+        assert_eq!(finished.load(Ordering::SeqCst), 4);
+        assert_eq!(POOL.live_tasks(), 0);
+    }
+
+    #[test]
+    fn full_pool_hands_future_back() {
+        task_pool!(static POOL: tasks = 1, size = 64);
+        let executor = Executor::new(&POOL);
+        POOL.spawn(async {}).ok().unwrap();
+        assert!(POOL.spawn(async {}).is_err());
+        executor.run_until_idle();
+        // The first task completed, freeing its slot.
+        assert!(POOL.spawn(async {}).is_ok());
+    }
+
+    #[test]
+    fn more_tasks_than_bits_in_a_word() {
+        task_pool!(static POOL: tasks = 40, size = 64);
+        let executor = Executor::new(&POOL);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..40 {
+            let order = order.clone();
+            POOL.spawn(async move { order.lock().unwrap().push(i) }).ok().unwrap();
+        }
+        executor.run_until_idle();
+        assert_eq!(*order.lock().unwrap(), (0..40).collect::<Vec<_>>());
+    }
+
+    /// Returns `Pending` until it has been polled `polls` times, waking its
+    /// task through a clone of the waker each time.
+    struct YieldTimes {
+        polls: usize,
+    }
+
+    impl Future for YieldTimes {
+        type Output = ();
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.polls == 0 {
+                return Poll::Ready(());
+            }
+            self.polls -= 1;
+            let waker = cx.waker().clone();
+            waker.wake();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn self_wakes_are_polled_before_returning() {
+        task_pool!(static POOL: tasks = 2, size = 64);
+        let executor = Executor::new(&POOL);
+        POOL.spawn(YieldTimes { polls: 100 }).ok().unwrap();
+        POOL.spawn(YieldTimes { polls: 3 }).ok().unwrap();
+        executor.run_until_idle();
+        assert_eq!(POOL.live_tasks(), 0);
+    }
+
+    #[test]
+    fn stale_waker_is_harmless() {
+        task_pool!(static POOL: tasks = 1, size = 64);
+        let executor = Executor::new(&POOL);
+        let saved = Arc::new(Mutex::new(None::<Waker>));
+        let task_saved = saved.clone();
+        POOL.spawn(core::future::poll_fn(move |cx| {
+            *task_saved.lock().unwrap() = Some(cx.waker().clone());
+            Poll::Ready(())
+        }))
+        .ok()
+        .unwrap();
+        executor.run_until_idle();
+        // The slot is free, so the wake only sets a bit which is skipped.
+        saved.lock().unwrap().take().unwrap().wake();
+        executor.run_until_idle();
+        assert_eq!(POOL.live_tasks(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "the pool already has an executor")]
+    fn second_executor_panics() {
+        task_pool!(static POOL: tasks = 1, size = 8);
+        let _first = Executor::new(&POOL);
+        Executor::new(&POOL);
+    }
+}
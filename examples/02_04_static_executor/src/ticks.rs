@@ -0,0 +1,244 @@
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/// A tick counter standing in for a hardware timer, with room for up to
+/// `TIMERS` pending `Delay`s. Nothing advances it on its own: tests call
+/// `advance`, from the executor's thread or from another thread.
+///
+/// `advance` takes the same spin lock as polling or dropping a `Delay`, so it
+/// must not be called from an interrupt handler, or anything else which may
+/// preempt a poll on the same core: it would spin forever on the lock held by
+/// the code it interrupted. A real timer driver would take the lock in a
+/// critical section with interrupts disabled instead.
+pub struct MockTicks<const TIMERS: usize> {
+    state: SpinLock<TickState<TIMERS>>,
+}
+
+struct TickState<const TIMERS: usize> {
+    now: u64,
+    /// The deadline and waker of each pending `Delay`.
+    timers: [Option<(u64, Waker)>; TIMERS],
+}
+
+/// A future which completes once its `MockTicks` reaches a deadline, like
+/// `TimerFuture` from the "Build a Timer" chapter counts down a duration.
+pub struct Delay<'a, const TIMERS: usize> {
+    ticks: &'a MockTicks<TIMERS>,
+    deadline: u64,
+    /// The entry in `TickState::timers` holding this delay's waker.
+    timer: Option<usize>,
+}
+
+/// A lock for `no_std` code, which spins until the lock is free. It is only
+/// held for a few instructions at a time.
+struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: the lock gives one thread at a time access to the value.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<const TIMERS: usize> MockTicks<TIMERS> {
+    pub const fn new() -> Self {
+        MockTicks {
+            state: SpinLock::new(TickState {
+                now: 0,
+                timers: [const { None }; TIMERS],
+            }),
+        }
+    }
+
+    /// The number of ticks so far.
+    pub fn now(&self) -> u64 {
+        self.state.with(|state| state.now)
+    }
+
+    /// Advance the counter by `ticks`, and wake every `Delay` whose deadline
+    /// has been reached. See the type's docs for where this may be called
+    /// from.
+    pub fn advance(&self, ticks: u64) {
+        let mut expired = [const { None }; TIMERS];
+        self.state.with(|state| {
+            state.now += ticks;
+            let now = state.now;
+            for (timer, expired) in state.timers.iter_mut().zip(&mut expired) {
+                if timer.as_ref().is_some_and(|(deadline, _)| *deadline <= now) {
+                    *expired = timer.take().map(|(_, waker)| waker);
+                }
+            }
+        });
+        // Wake outside of the lock, in case a waker polls a `Delay` itself.
+        expired.into_iter().flatten().for_each(Waker::wake);
+    }
+
+    /// A future which completes `ticks` ticks from now.
+    pub fn delay(&self, ticks: u64) -> Delay<'_, TIMERS> {
+        Delay {
+            ticks: self,
+            deadline: self.now() + ticks,
+            timer: None,
+        }
+    }
+}
+
+impl<const TIMERS: usize> Default for MockTicks<TIMERS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const TIMERS: usize> Future for Delay<'_, TIMERS> {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+        let registered = this.ticks.state.with(|state| {
+            if state.now >= this.deadline {
+                // `advance` already removed the entry.
+                this.timer = None;
+                return None;
+            }
+            // Until the deadline, this delay's entry is still its own.
+            let timer = match this.timer {
+                Some(timer) => timer,
+                None => match state.timers.iter().position(Option::is_none) {
+                    Some(timer) => timer,
+                    None => return Some(false),
+                },
+            };
+            match &mut state.timers[timer] {
+                Some((_, waker)) => waker.clone_from(cx.waker()),
+                entry => *entry = Some((this.deadline, cx.waker().clone())),
+            }
+            this.timer = Some(timer);
+            Some(true)
+        });
+        match registered {
+            None => Poll::Ready(()),
+            Some(true) => Poll::Pending,
+            Some(false) => panic!("too many pending delays for the `MockTicks`"),
+        }
+    }
+}
+
+impl<const TIMERS: usize> Drop for Delay<'_, TIMERS> {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer {
+            self.ticks.state.with(|state| {
+                // Past the deadline, `advance` has removed the entry, and it
+                // may belong to another delay by now.
+                if state.now < self.deadline {
+                    state.timers[timer] = None;
+                }
+            });
+        }
+    }
+}
+
+impl<T> SpinLock<T> {
+    const fn new(value: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        // SAFETY: the lock is held until `f` returns.
+        let result = f(unsafe { &mut *self.value.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{task_pool, Executor};
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+        vec::Vec,
+    };
+
+    #[test]
+    fn delays_complete_at_their_deadlines() {
+        task_pool!(static POOL: tasks = 3, size = 128);
+        static TICKS: MockTicks<3> = MockTicks::new();
+        let executor = Executor::new(&POOL);
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        for (name, ticks) in [("a", 30), ("b", 10), ("c", 20)] {
+            let finished = finished.clone();
+            POOL.spawn(async move {
+                TICKS.delay(ticks).await;
+                finished.lock().unwrap().push((name, TICKS.now()));
+            })
+            .ok()
+            .unwrap();
+        }
+        while POOL.live_tasks() > 0 {
+            executor.run_until_idle();
+            TICKS.advance(1);
+        }
+        assert_eq!(*finished.lock().unwrap(), [("b", 10), ("c", 20), ("a", 30)]);
+    }
+
+    #[test]
+    fn dropped_delay_frees_its_timer() {
+        task_pool!(static POOL: tasks = 1, size = 128);
+        static TICKS: MockTicks<1> = MockTicks::new();
+        let executor = Executor::new(&POOL);
+        POOL.spawn(async {
+            // Only one timer, so the second delay needs the first's entry.
+            {
+                let mut first = core::pin::pin!(TICKS.delay(100));
+                core::future::poll_fn(|cx| {
+                    assert!(first.as_mut().poll(cx).is_pending());
+                    Poll::Ready(())
+                })
+                .await;
+            }
+            TICKS.delay(5).await;
+        })
+        .ok()
+        .unwrap();
+        executor.run_until_idle();
+        TICKS.advance(5);
+        executor.run_until_idle();
+        assert_eq!(POOL.live_tasks(), 0);
+    }
+
+    #[test]
+    fn ticks_from_another_thread() {
+        task_pool!(static POOL: tasks = 1, size = 128);
+        static TICKS: MockTicks<1> = MockTicks::new();
+        let executor = Executor::new(&POOL);
+        POOL.spawn(async { TICKS.delay(3).await }).ok().unwrap();
+        executor.run_until_idle();
+        // Another thread can advance the ticks while a poll holds the lock,
+        // as it just waits for the poll to release it.
+        let interrupts = thread::spawn(|| {
+            for _ in 0..3 {
+                TICKS.advance(1);
+            }
+        });
+        while POOL.live_tasks() > 0 {
+            executor.run_until_idle();
+            thread::yield_now();
+        }
+        interrupts.join().unwrap();
+        assert_eq!(TICKS.now(), 3);
+    }
+}
@@ -125,6 +125,28 @@ async fn run_loop(
     }
 }
 // ANCHOR_END: fuse_terminated
+
+#[test]
+fn run_loop_with_interval() {
+    use std::time::Duration;
+    use timer_future::{interval, MockClock, TimerFuture};
+
+    let clock = MockClock::new();
+    let start = clock.now();
+    let mut ticks = 0;
+    // The clock skips ahead from one tick to the next, so this takes no time.
+    clock.block_on(async {
+        let interval_timer = interval(Duration::from_millis(10)).map(|_| ticks += 1);
+        // `run_loop` never returns, so stop it after a while. Biased, so that
+        // the tick due at the same time as the stop isn't taken.
+        futures::select_biased! {
+            () = TimerFuture::new(Duration::from_millis(100)).fuse() => {},
+            () = run_loop(interval_timer, 1).fuse() => unreachable!(),
+        }
+    });
+    assert_eq!(clock.now(), start + Duration::from_millis(100));
+    assert_eq!(ticks, 10);
+}
 }
 
 mod futures_unordered {
@@ -21,6 +21,7 @@ async fn main() {
 // ANCHOR_END: main_func
 
 use async_std::io::{Read, Write};
+use executor::spawn_blocking;
 
 async fn handle_connection(mut stream: impl Read + Write + Unpin) {
     let mut buffer = [0; 1024];
@@ -31,7 +32,12 @@ async fn handle_connection(mut stream: impl Read + Write + Unpin) {
     } else {
         ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
     };
-    let contents = fs::read_to_string(filename).unwrap();
+    // Reading the file blocks, so do it on the blocking pool rather than on
+    // one of async-std's executor threads.
+    let contents = spawn_blocking(move || fs::read_to_string(filename))
+        .await
+        .unwrap()
+        .unwrap();
     let response = format!("{status_line}{contents}");
     stream.write(response.as_bytes()).await.unwrap();
     stream.flush().await.unwrap();